@@ -1,8 +1,8 @@
-use std::{error::Error, fmt::Display};
+use std::{error::Error, fmt::Display, io};
 
 use crossbeam::channel::SendError;
 
-use crate::MAX_MESSAGE_LEN;
+use crate::{DisconnectReason, MAX_MESSAGE_LEN};
 
 /// Describes possible errors
 #[derive(Debug)]
@@ -11,10 +11,14 @@ pub enum NetError {
     UnknownPeer,
     /// Peer is not able to communicate with other peers anymore.
     Disconnected,
-    /// Tried to send a message longer than `MAX_MESSAGE_LEN`.
+    /// Tried to send a message longer than `Settings::max_message_len`, or a login payload longer than `MAX_MESSAGE_LEN`.
     MessageTooLong,
     /// Unreliable message was instantly dropped because there are too many packets waiting to be sent.
     Dropped,
+    /// Tried to do something only the host can do.
+    NotHost,
+    /// Tried to send a message before the host has registered this peer.
+    NotConnected,
 }
 
 impl Display for NetError {
@@ -22,10 +26,14 @@ impl Display for NetError {
         match self {
             NetError::UnknownPeer => write!(f, "No peer with this id"),
             NetError::Disconnected => write!(f, "Not connected"),
-            NetError::MessageTooLong => {
-                write!(f, "Message len exceeds the limit of {}", MAX_MESSAGE_LEN)
-            }
+            NetError::MessageTooLong => write!(
+                f,
+                "Message len exceeds the limit (Settings::max_message_len, or {} for a login payload)",
+                MAX_MESSAGE_LEN
+            ),
             NetError::Dropped => write!(f, "Message dropped"),
+            NetError::NotHost => write!(f, "Only the host can do this"),
+            NetError::NotConnected => write!(f, "Not connected yet"),
         }
     }
 }
@@ -37,3 +45,35 @@ impl<T> From<SendError<T>> for NetError {
         Self::Disconnected
     }
 }
+
+/// Why a client couldn't connect to the host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectError {
+    /// Host didn't answer within `Settings::connect_timeout`.
+    Unreachable,
+    /// Host has turned down the login, e.g. with `DisconnectReason::Rejected` or `DisconnectReason::Banned`.
+    Rejected(DisconnectReason),
+    /// Host uses an incompatible version of the protocol.
+    ProtocolMismatch,
+    /// Still not connected when `Peer::wait_connected` stopped waiting.
+    Timeout,
+    /// Has been connected, but the connection is gone already.
+    Disconnected(DisconnectReason),
+    /// Couldn't set up the socket.
+    Io(io::ErrorKind),
+}
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectError::Unreachable => write!(f, "Host is unreachable"),
+            ConnectError::Rejected(reason) => write!(f, "Rejected by the host: {:?}", reason),
+            ConnectError::ProtocolMismatch => write!(f, "Host uses another protocol version"),
+            ConnectError::Timeout => write!(f, "Timed out while connecting"),
+            ConnectError::Disconnected(reason) => write!(f, "Disconnected: {:?}", reason),
+            ConnectError::Io(kind) => write!(f, "Socket error: {}", kind),
+        }
+    }
+}
+
+impl Error for ConnectError {}
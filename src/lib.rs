@@ -1,30 +1,45 @@
 //! Tangled - a work-in-progress UDP networking crate.
 
 use std::{
-    fmt::Display, io, net::{SocketAddr, UdpSocket}, sync::{atomic::AtomicBool, Arc}
+    fmt::Display, fs, io::{self, ErrorKind, Read}, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket}, path::Path, sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering::SeqCst}, Arc, Condvar, Mutex
+    }, time::{Duration, Instant}
 };
 
 use crossbeam::{
     self, atomic::AtomicCell, channel::{unbounded, Receiver, Sender}
 };
 
-pub use error::NetError;
+pub use error::{ConnectError, NetError};
 use reactor::{Destination, RemotePeer, Shared};
 pub use reactor::{Reliability, Settings};
 use serde::{Deserialize, Serialize};
+use util::CongestionControl;
 
-const DATAGRAM_MAX_LEN: usize = 1500;
+/// Largest possible UDP payload. Datagrams are never larger than this, whatever path MTU discovery finds.
+const DATAGRAM_MAX_LEN: usize = 65507;
+
+/// Size of datagrams to peers with an unknown path MTU, small enough to not be fragmented on most links.
+const DATAGRAM_DEFAULT_LEN: usize = 1400;
 
 /// Maximum size of a message which fits into a single datagram.
+/// Longer messages are split into fragments, up to `Settings::max_message_len`.
+/// Fragments can be larger when path MTU discovery finds that larger datagrams get through, see `Settings::mtu_discovery`.
 pub const MAX_MESSAGE_LEN: usize = 1200;
 
+/// Longest `Peer::disconnect` waits for the networking threads to stop.
+const DISCONNECT_JOIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Starts the lines of banned login payloads in the files written by `Peer::save_bans`.
+const IDENTITY_BAN_PREFIX: &str = "identity ";
+
 mod error;
 mod reactor;
+mod transfer;
 mod util;
 
 struct Datagram {
-    pub size: usize,
-    pub data: [u8; DATAGRAM_MAX_LEN],
+    pub data: Vec<u8>,
 }
 
 /// A value which refers to a specific peer.
@@ -38,35 +53,140 @@ impl Display for PeerId {
     }
 }
 
+/// Identifies an independent stream of messages between peers.
+/// Ordering and sequencing are only guaranteed within a single channel, so a message that is being resent on one channel doesn't delay the others.
+/// Channel 0 is used by default.
+#[derive(Debug, Default, Hash, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct ChannelId(pub u8);
+
+impl Display for ChannelId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 type SeqId = u16;
 
+/// Refers to a message sent by `Peer::send`. Used to report whether a reliable message has been delivered.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
+pub struct MessageHandle(u64);
+
+/// Identifies a stream transfer started by `Peer::send_stream`.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct TransferId {
+    /// The peer that sends the stream.
+    pub sender: PeerId,
+    /// Number of the transfer, unique for every sender.
+    pub id: u32,
+}
+
 /// Possible network events, returned by `Peer.recv()`.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum NetworkEvent {
     /// A new peer has connected.
     PeerConnected(PeerId),
     /// Peer has disconnected.
-    PeerDisconnected(PeerId),
+    PeerDisconnected {
+        id: PeerId,
+        reason: DisconnectReason,
+    },
+    /// Connection to a peer has been lost, but it may still come back within `Settings::resume_grace_period`.
+    /// Followed by either `PeerResumed` or `PeerDisconnected`.
+    PeerReconnecting(PeerId),
+    /// A reconnecting peer is back, with the same id.
+    PeerResumed(PeerId),
+    /// State of this peer has changed, see `Peer::state`.
+    StateChanged(PeerState),
+    /// A ping to a direct peer has been answered. Only reported with `Settings::latency_events`.
+    LatencyUpdate { id: PeerId, stats: PeerStats },
     /// Message has been received.
     Message(Message),
+    /// Reliable message has been acknowledged.
+    /// Messages to other clients are acknowledged by the host, which will relay them.
+    Delivered(MessageHandle),
+    /// Reliable message won't be delivered, as it has been given up on or the connection over which it was sent has been lost.
+    Lost(MessageHandle),
+    /// A remote peer has started sending a stream to this one.
+    TransferStarted(TransferId),
+    /// Another part of a stream has been received. `received` is the amount of bytes received so far.
+    TransferProgress { id: TransferId, received: u64 },
+    /// A stream has been received completely.
+    TransferCompleted { id: TransferId, data: Vec<u8> },
+    /// A stream has been cancelled or has timed out. Reported on both sides of the transfer.
+    TransferFailed(TransferId),
+    /// A client wants to connect, sending `payload` along, see `Peer::connect_with_payload`.
+    /// Only reported to the host with `Settings::manual_accept`, which has to either `Peer::accept` or `Peer::reject` it.
+    ConnectionRequest { addr: SocketAddr, payload: Vec<u8> },
+}
+
+/// Latency to a direct peer, measured by pings every `Settings::ping_interval`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerStats {
+    /// Round-trip time of the latest ping.
+    pub ping: Duration,
+    /// Smoothed round-trip time, the same as `Peer::rtt` reports.
+    pub rtt: Duration,
+    /// How much the round-trip time varies between pings.
+    pub jitter: Duration,
+    /// How far the clock of the peer is ahead of the local one, in microseconds. Negative if it's behind.
+    pub clock_offset: i64,
 }
 
 /// A message received from a peer.
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Message {
     //src: PeerId,
+    /// The channel this message has been sent on.
+    pub channel: ChannelId,
     /// The data that has been sent.
     pub data: Vec<u8>,
 }
 
+/// Additional parameters for `Peer::send_with`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SendOptions {
+    /// The channel to send the message on.
+    pub channel: ChannelId,
+    /// The message is dropped if it wasn't sent, or confirmed in case of a reliable one, until this moment.
+    /// Combined with `Settings::max_message_age`, whichever comes first. Ignored for `ReliableOrdered` messages.
+    pub deadline: Option<Instant>,
+}
+
 struct OutboundMessage {
     pub dst: Destination,
+    pub channel: ChannelId,
+    pub handle: MessageHandle,
+    pub deadline: Option<Instant>,
     pub data: Vec<u8>,
     pub reliability: Reliability,
 }
 
+pub(crate) enum Command {
+    SendStream {
+        id: TransferId,
+        dst: PeerId,
+        reader: Box<dyn Read + Send>,
+    },
+    CancelTransfer(TransferId),
+    Disconnect,
+    Shutdown(Instant),
+    Kick {
+        id: PeerId,
+        message: String,
+    },
+    Ban {
+        id: PeerId,
+        message: String,
+    },
+    Accept(SocketAddr),
+    Reject {
+        addr: SocketAddr,
+        message: String,
+    },
+}
+
 /// Current peer state
-#[derive(Default, Clone, Copy, Debug)]
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
 pub enum PeerState {
     /// Waiting for connection. Switches to `Connected` right after id from the host has been acquired.
     /// Note: hosts switches to 'Connected' basically instantly.
@@ -74,8 +194,36 @@ pub enum PeerState {
     PendingConnection,
     /// Connected to host and ready to send/receive messages.
     Connected,
+    /// Connection to the host has been lost, trying to resume the session. See `Settings::resume_grace_period`.
+    Reconnecting,
     /// No longer connected, won't reconnect.
-    Disconnected,
+    Disconnected(DisconnectReason),
+    /// Couldn't connect to the host, won't try again.
+    ConnectFailed(ConnectError),
+}
+
+/// Why a peer has been disconnected.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    /// Nothing has been received from the peer for `Settings::connection_timeout`.
+    Timeout,
+    /// Peer has left on its own, by calling `Peer::disconnect` or being dropped.
+    Left,
+    /// Peer has been kicked by the host, with a message telling why.
+    Kicked(String),
+    /// Peer has been banned by the host, with a message telling why.
+    /// Also given to clients which try to connect from a banned address.
+    Banned(String),
+    /// Peer has sent something that doesn't follow the protocol, or kept sending datagrams that can't be decoded.
+    ProtocolError,
+    /// Host has ended the session.
+    HostShutdown,
+    /// Host has not accepted the login, with a message telling why.
+    Rejected(String),
+    /// Networking has stopped because of a local error, e.g. the socket has failed.
+    Error,
+    /// Host doesn't know the session the client tried to resume, e.g. because the grace period is over.
+    SessionExpired,
 }
 
 type Channel<T> = (Sender<T>, Receiver<T>);
@@ -91,25 +239,40 @@ impl Peer {
     fn new(
         bind_addr: SocketAddr,
         host_addr: Option<SocketAddr>,
+        login_payload: Vec<u8>,
         settings: Option<Settings>,
     ) -> io::Result<Self> {
         let socket = UdpSocket::bind(bind_addr)?;
         //socket.set_read_timeout(Some(Duration::from_millis(500)))?;
+        if settings
+            .as_ref()
+            .is_some_and(|settings| settings.mtu_discovery)
+        {
+            util::set_dont_fragment(&socket)?;
+        }
         let shared = Arc::new(Shared {
             socket,
             inbound_channel: unbounded(),
             outbound_channel: unbounded(),
+            command_channel: unbounded(),
             keep_alive: AtomicBool::new(true),
             host_addr,
-            peer_state: Default::default(),
+            peer_state: Mutex::default(),
+            state_changed: Condvar::new(),
+            shutting_down: AtomicBool::new(false),
+            threads: Mutex::default(),
+            bans: Default::default(),
+            identity_bans: Default::default(),
             remote_peers: Default::default(),
-            max_packets_per_second: 256,
             my_id: AtomicCell::new(if host_addr.is_none() {
                 Some(PeerId(0))
             } else {
                 None
             }),
+            login_payload,
             settings: settings.unwrap_or_default(),
+            transfer_counter: AtomicU32::new(0),
+            message_counter: AtomicU64::new(0),
         });
         if host_addr.is_none() {
             shared.remote_peers.insert(PeerId(0), RemotePeer::default());
@@ -120,37 +283,230 @@ impl Peer {
 
     /// Host at a specified `bind_addr`.
     pub fn host(bind_addr: SocketAddr, settings: Option<Settings>) -> io::Result<Self> {
-        Self::new(bind_addr, None, settings)
+        Self::new(bind_addr, None, Vec::new(), settings)
     }
 
     /// Connect to a specified `host_addr`.
     pub fn connect(host_addr: SocketAddr, settings: Option<Settings>) -> io::Result<Self> {
-        Self::new("0.0.0.0:0".parse().unwrap(), Some(host_addr), settings)
+        Self::connect_with_payload(host_addr, Vec::new(), settings)
+    }
+
+    /// Connect to a specified `host_addr`, sending `payload` along with the login, e.g. a name or an auth token.
+    /// The host sees it in a `ConnectionRequest` event if it uses `Settings::manual_accept`.
+    /// The payload can be at most `MAX_MESSAGE_LEN` bytes long.
+    pub fn connect_with_payload(
+        host_addr: SocketAddr,
+        payload: Vec<u8>,
+        settings: Option<Settings>,
+    ) -> io::Result<Self> {
+        if payload.len() > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                NetError::MessageTooLong,
+            ));
+        }
+        Self::new(
+            "0.0.0.0:0".parse().unwrap(),
+            Some(host_addr),
+            payload,
+            settings,
+        )
+    }
+
+    /// Connect to a specified `host_addr`, waiting until the host has registered this peer.
+    /// Gives up after `timeout`, or earlier if connecting fails, see `Settings::connect_timeout`.
+    pub fn connect_blocking(
+        host_addr: SocketAddr,
+        settings: Option<Settings>,
+        timeout: Duration,
+    ) -> Result<Self, ConnectError> {
+        let peer =
+            Self::connect(host_addr, settings).map_err(|err| ConnectError::Io(err.kind()))?;
+        peer.wait_connected(timeout)?;
+        Ok(peer)
+    }
+
+    /// Wait until the host has registered this peer, returning the assigned id.
+    /// Returns right away if already connected, as well as for the host itself.
+    pub fn wait_connected(&self, timeout: Duration) -> Result<PeerId, ConnectError> {
+        let state = self.shared.peer_state.lock().unwrap();
+        let (state, _) = self
+            .shared
+            .state_changed
+            .wait_timeout_while(state, timeout, |state| {
+                matches!(state, PeerState::PendingConnection)
+            })
+            .unwrap();
+        match &*state {
+            PeerState::PendingConnection => Err(ConnectError::Timeout),
+            PeerState::Connected | PeerState::Reconnecting => Ok(self
+                .my_id()
+                .expect("Id is assigned before the state changes")),
+            PeerState::Disconnected(reason) => Err(ConnectError::Disconnected(reason.clone())),
+            PeerState::ConnectFailed(err) => Err(err.clone()),
+        }
     }
 
     /// Send a message to a specified single peer.
+    /// For reliable messages, the returned handle is later reported in either a `Delivered` or a `Lost` event.
+    /// Fails with `UnknownPeer` if the destination isn't connected, a peer that leaves before the message is sent gets it reported as lost.
+    /// Fails with `NotConnected` until the connection to the host is established.
     pub fn send(
         &self,
         destination: PeerId,
         data: Vec<u8>,
         reliability: Reliability,
-    ) -> Result<(), NetError> {
-        if data.len() > MAX_MESSAGE_LEN {
+    ) -> Result<MessageHandle, NetError> {
+        self.send_on(ChannelId::default(), destination, data, reliability)
+    }
+
+    /// Send a message to a specified single peer on a specified channel.
+    pub fn send_on(
+        &self,
+        channel: ChannelId,
+        destination: PeerId,
+        data: Vec<u8>,
+        reliability: Reliability,
+    ) -> Result<MessageHandle, NetError> {
+        let options = SendOptions {
+            channel,
+            ..Default::default()
+        };
+        self.send_with(destination, data, reliability, options)
+    }
+
+    /// Send a message to a specified single peer, with additional options.
+    pub fn send_with(
+        &self,
+        destination: PeerId,
+        data: Vec<u8>,
+        reliability: Reliability,
+        options: SendOptions,
+    ) -> Result<MessageHandle, NetError> {
+        if self.shared.shutting_down.load(SeqCst) {
+            return Err(NetError::Disconnected);
+        }
+        if self.shared.my_id.load().is_none() {
+            return Err(NetError::NotConnected);
+        }
+        if !self.shared.remote_peers.contains_key(&destination) {
+            return Err(NetError::UnknownPeer);
+        }
+        // Fragments are at least `MAX_MESSAGE_LEN` long and are counted with a u16.
+        let max_len = self
+            .shared
+            .settings
+            .max_message_len
+            .min(MAX_MESSAGE_LEN * u16::MAX as usize);
+        if data.len() > max_len {
             return Err(NetError::MessageTooLong);
         }
-        if reliability == Reliability::Unreliable
-            && self.shared.outbound_channel.0.len() * 2 > self.shared.max_packets_per_second
-        {
-            return Err(NetError::Dropped);
+        if !reliability.is_reliable() {
+            // Clients send everything through the host.
+            let direct = if self.shared.host_addr.is_some() {
+                PeerId(0)
+            } else {
+                destination
+            };
+            let rate = self
+                .send_rate(direct)
+                .unwrap_or(CongestionControl::INITIAL_RATE);
+            if self.shared.outbound_channel.0.len() as f64 * 2.0 > rate {
+                return Err(NetError::Dropped);
+            }
         }
+        let handle = MessageHandle(self.shared.message_counter.fetch_add(1, SeqCst));
+        let max_age_deadline = self
+            .shared
+            .settings
+            .max_message_age
+            .map(|age| Instant::now() + age);
         self.shared.outbound_channel.0.send(OutboundMessage {
             dst: Destination::One(destination),
+            channel: options.channel,
+            handle,
+            deadline: options.deadline.into_iter().chain(max_age_deadline).min(),
             data,
             reliability,
         })?;
+        Ok(handle)
+    }
+
+    /// Start sending a stream of data, read from `reader`, to a specified single peer.
+    /// The stream is sent reliably, in parts, paced apart from normal messages so that it doesn't slow them down.
+    /// The receiving side gets `TransferStarted`, `TransferProgress` and `TransferCompleted` events.
+    pub fn send_stream(
+        &self,
+        destination: PeerId,
+        reader: impl Read + Send + 'static,
+    ) -> Result<TransferId, NetError> {
+        if self.shared.shutting_down.load(SeqCst) {
+            return Err(NetError::Disconnected);
+        }
+        let id = TransferId {
+            sender: self.my_id().ok_or(NetError::NotConnected)?,
+            id: self.shared.transfer_counter.fetch_add(1, SeqCst),
+        };
+        self.shared.command_channel.0.send(Command::SendStream {
+            id,
+            dst: destination,
+            reader: Box::new(reader),
+        })?;
+        Ok(id)
+    }
+
+    /// Cancel a stream transfer. Can be used by both the sending and the receiving side.
+    pub fn cancel_transfer(&self, id: TransferId) -> Result<(), NetError> {
+        self.shared
+            .command_channel
+            .0
+            .send(Command::CancelTransfer(id))?;
         Ok(())
     }
 
+    /// Tell every directly connected peer that this one is leaving, so that they don't have to wait for `Settings::connection_timeout`, and stop.
+    /// For the host this ends the session for every client.
+    /// Waits a short while for the networking threads to stop. Also happens when the peer is dropped.
+    pub fn disconnect(&self) {
+        self.shared.command_channel.0.send(Command::Disconnect).ok();
+        self.join_threads(Some(Instant::now() + DISCONNECT_JOIN_TIMEOUT));
+    }
+
+    /// Stop sending, wait up to `timeout` for the reliable messages that are already sent to be confirmed, then disconnect and stop the networking threads.
+    /// The port is released once every clone of this peer is dropped as well.
+    pub fn shutdown(self, timeout: Duration) {
+        self.shared.shutting_down.store(true, SeqCst);
+        self.shared
+            .command_channel
+            .0
+            .send(Command::Shutdown(Instant::now() + timeout))
+            .ok();
+        self.join_threads(None);
+    }
+
+    /// Waits for the reactor thread to stop, then wakes up the pipe thread and waits for it as well.
+    /// Threads that are still running at `deadline` are left to stop on their own.
+    fn join_threads(&self, deadline: Option<Instant>) {
+        let Some((reactor_thread, pipe_thread)) = self.shared.threads.lock().unwrap().take() else {
+            return;
+        };
+        if !util::join_until(reactor_thread, deadline) {
+            return;
+        }
+        // The pipe thread waits for a datagram, so it's given an empty one.
+        self.shared.keep_alive.store(false, SeqCst);
+        if let Ok(mut addr) = self.shared.socket.local_addr() {
+            if addr.ip().is_unspecified() {
+                addr.set_ip(match addr {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            self.shared.socket.send_to(&[], addr).ok();
+        }
+        util::join_until(pipe_thread, deadline);
+    }
+
     /// Return an iterator over recieved messages.
     /// Does not block.
     pub fn recv(&self) -> impl Iterator<Item = NetworkEvent> + '_ {
@@ -171,7 +527,168 @@ impl Peer {
 
     /// Current state of the peer.
     pub fn state(&self) -> PeerState {
-        self.shared.peer_state.load()
+        self.shared.peer_state.lock().unwrap().clone()
+    }
+
+    /// Smoothed round-trip time to a specified peer, measured by pings and by confirms of reliable messages.
+    /// Only known for peers this one communicates with directly, i.e. every peer for the host and only the host for clients.
+    pub fn rtt(&self, peer: PeerId) -> Option<Duration> {
+        self.shared.remote_peers.get(&peer)?.rtt
+    }
+
+    /// Packets per second that can currently be sent to a specified peer, as decided by congestion control.
+    /// Only known for peers this one communicates with directly, after some of the sent messages have been confirmed.
+    pub fn send_rate(&self, peer: PeerId) -> Option<f64> {
+        self.shared.remote_peers.get(&peer)?.send_rate
+    }
+
+    /// Largest datagram, in bytes, that is known to get through to a specified peer.
+    /// Only known for peers this one communicates with directly, and only grows past the default with `Settings::mtu_discovery`.
+    pub fn mtu(&self, peer: PeerId) -> Option<usize> {
+        self.shared.remote_peers.get(&peer)?.mtu
+    }
+
+    /// Disconnect a client, telling it why. Everyone else is told that the client is gone.
+    /// Only the host can kick.
+    pub fn kick(&self, peer: PeerId, message: impl Into<String>) -> Result<(), NetError> {
+        self.check_kickable(peer)?;
+        self.shared.command_channel.0.send(Command::Kick {
+            id: peer,
+            message: message.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Kick a client and ban its IP address, so that no one can connect from it anymore.
+    /// The payload it has logged in with is banned as well, unless it's empty.
+    /// Only the host can ban.
+    pub fn ban(&self, peer: PeerId, message: impl Into<String>) -> Result<(), NetError> {
+        self.check_kickable(peer)?;
+        self.shared.command_channel.0.send(Command::Ban {
+            id: peer,
+            message: message.into(),
+        })?;
+        Ok(())
+    }
+
+    /// Let in a client that sent a `ConnectionRequest`.
+    pub fn accept(&self, addr: SocketAddr) -> Result<(), NetError> {
+        if self.shared.host_addr.is_some() {
+            return Err(NetError::NotHost);
+        }
+        self.shared.command_channel.0.send(Command::Accept(addr))?;
+        Ok(())
+    }
+
+    /// Turn down a client that sent a `ConnectionRequest`, telling it why.
+    /// The client ends up in `PeerState::ConnectFailed` with `DisconnectReason::Rejected`.
+    pub fn reject(&self, addr: SocketAddr, message: impl Into<String>) -> Result<(), NetError> {
+        if self.shared.host_addr.is_some() {
+            return Err(NetError::NotHost);
+        }
+        self.shared.command_channel.0.send(Command::Reject {
+            addr,
+            message: message.into(),
+        })?;
+        Ok(())
+    }
+
+    fn check_kickable(&self, peer: PeerId) -> Result<(), NetError> {
+        if self.shared.host_addr.is_some() {
+            return Err(NetError::NotHost);
+        }
+        if peer == PeerId(0) || !self.shared.remote_peers.contains_key(&peer) {
+            return Err(NetError::UnknownPeer);
+        }
+        Ok(())
+    }
+
+    /// Ban an IP address without kicking anyone. Clients that are already connected from it stay connected.
+    pub fn ban_addr(&self, addr: IpAddr) {
+        self.shared.bans.insert(addr);
+    }
+
+    /// Lift a ban. Returns whether the address was banned.
+    pub fn unban(&self, addr: IpAddr) -> bool {
+        self.shared.bans.remove(&addr).is_some()
+    }
+
+    /// Currently banned IP addresses.
+    pub fn bans(&self) -> Vec<IpAddr> {
+        self.shared.bans.iter().map(|addr| *addr).collect()
+    }
+
+    /// Ban a login payload without kicking anyone, so that no one can connect with it anymore, from any address.
+    /// Meant for payloads that identify the user, like an account name or token.
+    pub fn ban_identity(&self, payload: impl Into<Vec<u8>>) {
+        self.shared.identity_bans.insert(payload.into());
+    }
+
+    /// Lift a ban of a login payload. Returns whether it was banned.
+    pub fn unban_identity(&self, payload: &[u8]) -> bool {
+        self.shared.identity_bans.remove(payload).is_some()
+    }
+
+    /// Currently banned login payloads.
+    pub fn identity_bans(&self) -> Vec<Vec<u8>> {
+        self.shared
+            .identity_bans
+            .iter()
+            .map(|payload| payload.clone())
+            .collect()
+    }
+
+    /// Write the banned addresses and login payloads to a file, one per line.
+    /// Payloads are written in hex, after `identity `.
+    pub fn save_bans(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let addrs = self.bans().into_iter().map(|addr| format!("{}\n", addr));
+        let identities = self.identity_bans().into_iter().map(|payload| {
+            let hex: String = payload.iter().map(|byte| format!("{:02x}", byte)).collect();
+            format!("{}{}\n", IDENTITY_BAN_PREFIX, hex)
+        });
+        fs::write(path, addrs.chain(identities).collect::<String>())
+    }
+
+    /// Ban the addresses and login payloads from a file written by `save_bans`, in addition to the current bans.
+    pub fn load_bans(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let contents = fs::read_to_string(path)?;
+        let mut addrs = Vec::new();
+        let mut identities = Vec::new();
+        for line in contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            if let Some(hex) = line.strip_prefix(IDENTITY_BAN_PREFIX) {
+                let payload = decode_hex(hex).ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "Invalid hex in an identity ban")
+                })?;
+                identities.push(payload);
+            } else {
+                addrs.push(
+                    line.parse::<IpAddr>()
+                        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?,
+                );
+            }
+        }
+        for addr in addrs {
+            self.ban_addr(addr);
+        }
+        for payload in identities {
+            self.ban_identity(payload);
+        }
+        Ok(())
+    }
+
+    /// Latency to a specified peer, as measured by pings.
+    /// Only known for peers this one communicates with directly, once a ping has been answered.
+    pub fn peer_stats(&self, peer: PeerId) -> Option<PeerStats> {
+        let remote = self.shared.remote_peers.get(&peer)?;
+        // Confirms may have updated the round-trip time since the latest ping.
+        remote.stats.map(|stats| PeerStats {
+            rtt: remote.rtt.unwrap_or(stats.rtt),
+            ..stats
+        })
     }
 
     /// Iterate over connected peers, returning ther `PeerId`.
@@ -183,19 +700,110 @@ impl Peer {
     }
 }
 
+/// Decodes pairs of hex digits, as written by `Peer::save_bans`.
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
 impl Drop for Peer {
     fn drop(&mut self) {
-        self.shared
-            .keep_alive
-            .store(false, std::sync::atomic::Ordering::SeqCst)
+        self.disconnect();
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::{thread, time::Duration};
+    use std::{
+        io, net::{SocketAddr, UdpSocket}, path::PathBuf, sync::{
+            atomic::{AtomicBool, Ordering::SeqCst}, Arc
+        }, thread, time::{Duration, Instant}
+    };
+
+    use crossbeam::channel::{unbounded, Receiver};
+
+    use crate::{
+        reactor::Settings, ChannelId, ConnectError, DisconnectReason, Message, NetError, NetworkEvent, Peer, PeerId, PeerState, Reliability, SendOptions
+    };
 
-    use crate::{reactor::Settings, Message, NetworkEvent, Peer, PeerId, Reliability};
+    /// Longest the tests wait for something that should happen almost right away.
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// File in the temporary directory that is removed once the test is done, even if it fails.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        /// The name is made unique to the test and the process, as tests run in parallel.
+        fn new(test: &str) -> Self {
+            let name = format!("tangled_test_{}_{}.txt", test, std::process::id());
+            Self(std::env::temp_dir().join(name))
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+
+    fn local_addr(peer: &Peer) -> SocketAddr {
+        peer.shared.socket.local_addr().unwrap()
+    }
+
+    /// Hosts on a free port and connects a client to it.
+    fn host_and_client(settings: Option<Settings>) -> (Peer, Peer) {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings.clone()).unwrap();
+        let client = Peer::connect_blocking(local_addr(&host), settings, TIMEOUT).unwrap();
+        (host, client)
+    }
+
+    /// Collects events up to and including the first one that `done` returns true for.
+    fn recv_until(peer: &Peer, mut done: impl FnMut(&NetworkEvent) -> bool) -> Vec<NetworkEvent> {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut events = Vec::new();
+        loop {
+            let event = peer
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                .expect("Timed out waiting for an event");
+            let found = done(&event);
+            events.push(event);
+            if found {
+                return events;
+            }
+        }
+    }
+
+    /// Polls `condition` until it holds.
+    fn wait_until(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !condition() {
+            assert!(
+                Instant::now() < deadline,
+                "Timed out waiting for a condition"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// Waits for `count` messages, dropping any other events.
+    fn recv_messages(peer: &Peer, count: usize) -> Vec<Message> {
+        let mut messages = Vec::new();
+        recv_until(peer, |event| {
+            if let NetworkEvent::Message(msg) = event {
+                messages.push(msg.clone());
+            }
+            messages.len() == count
+        });
+        messages
+    }
 
     #[test_log::test]
     fn test_peer() {
@@ -204,28 +812,1017 @@ mod test {
             connection_timeout: Duration::from_millis(1000),
             ..Default::default()
         });
-        let addr = "127.0.0.1:56001".parse().unwrap();
-        let host = Peer::host(addr, settings.clone()).unwrap();
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings.clone()).unwrap();
         assert_eq!(host.shared.remote_peers.len(), 1);
-        let peer = Peer::connect(addr, settings.clone()).unwrap();
-        thread::sleep(Duration::from_millis(100));
-        assert_eq!(peer.shared.remote_peers.len(), 2);
+        let peer = Peer::connect_blocking(local_addr(&host), settings, TIMEOUT).unwrap();
         assert_eq!(host.shared.remote_peers.len(), 2);
+        // The client learns about itself from the host, after the registration.
+        wait_until(|| peer.shared.remote_peers.len() == 2);
         let data = vec![128, 51, 32];
-        peer.send(PeerId(0), data.clone(), Reliability::Reliable)
+        let handle = peer
+            .send(PeerId(0), data.clone(), Reliability::Reliable)
             .unwrap();
-        thread::sleep(Duration::from_millis(10));
-        let host_events: Vec<_> = host.recv().collect();
+        let host_events = recv_until(&host, |event| matches!(event, NetworkEvent::Message(_)));
         assert!(host_events.contains(&NetworkEvent::PeerConnected(PeerId(1))));
-        assert!(host_events.contains(&NetworkEvent::Message(Message { data })));
-        let peer_events: Vec<_> = peer.recv().collect();
+        assert!(host_events.contains(&NetworkEvent::Message(Message {
+            channel: ChannelId::default(),
+            data
+        })));
+        let peer_events = recv_until(&peer, |event| matches!(event, NetworkEvent::Delivered(_)));
         assert!(peer_events.contains(&NetworkEvent::PeerConnected(PeerId(0))));
+        assert_eq!(peer_events.last(), Some(&NetworkEvent::Delivered(handle)));
+        assert!(peer.rtt(PeerId(0)).is_some());
+        assert!(peer.send_rate(PeerId(0)).is_some());
         drop(peer);
-        thread::sleep(Duration::from_millis(1200));
+        let host_events = recv_until(&host, |event| {
+            matches!(event, NetworkEvent::PeerDisconnected { .. })
+        });
         assert_eq!(
-            host.recv().next(),
-            Some(NetworkEvent::PeerDisconnected(PeerId(1)))
+            host_events.last(),
+            Some(&NetworkEvent::PeerDisconnected {
+                id: PeerId(1),
+                reason: DisconnectReason::Left
+            })
+        );
+        wait_until(|| host.shared.remote_peers.len() == 1);
+    }
+
+    #[test_log::test]
+    fn test_send_unknown_peer() {
+        let (host, peer) = host_and_client(None);
+        assert!(matches!(
+            host.send(PeerId(42), vec![1], Reliability::Reliable),
+            Err(NetError::UnknownPeer)
+        ));
+
+        // Known, but the host isn't a direct peer of itself, so it can't be sent anywhere.
+        let handle = host
+            .send(PeerId(0), vec![1], Reliability::Reliable)
+            .unwrap();
+        let host_events = recv_until(&host, |event| {
+            matches!(event, NetworkEvent::Lost(_) | NetworkEvent::Delivered(_))
+        });
+        assert_eq!(host_events.last(), Some(&NetworkEvent::Lost(handle)));
+
+        let id = peer.my_id().unwrap();
+        drop(peer);
+        recv_until(&host, |event| {
+            matches!(event, NetworkEvent::PeerDisconnected { .. })
+        });
+        assert!(matches!(
+            host.send(id, vec![1], Reliability::Reliable),
+            Err(NetError::UnknownPeer)
+        ));
+    }
+
+    #[test_log::test]
+    fn test_send_before_connected() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let blocked = Arc::new(AtomicBool::new(true));
+        let relay_addr = flaky_relay(local_addr(&host), blocked.clone());
+        let peer = Peer::connect(relay_addr, None).unwrap();
+        assert_eq!(peer.state(), PeerState::PendingConnection);
+        assert!(matches!(
+            peer.send(PeerId(0), vec![1], Reliability::Reliable),
+            Err(NetError::NotConnected)
+        ));
+        assert!(matches!(
+            peer.send_stream(PeerId(0), io::empty()),
+            Err(NetError::NotConnected)
+        ));
+
+        // Still works once connected.
+        blocked.store(false, SeqCst);
+        peer.wait_connected(TIMEOUT).unwrap();
+        peer.send(PeerId(0), vec![2], Reliability::Reliable)
+            .unwrap();
+        assert_eq!(recv_messages(&host, 1)[0].data, vec![2]);
+    }
+
+    #[test_log::test]
+    fn test_disconnect() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = local_addr(&host);
+        let peer1 = Peer::connect_blocking(addr, None, TIMEOUT).unwrap();
+        let peer2 = Peer::connect_blocking(addr, None, TIMEOUT).unwrap();
+        recv_until(&host, |event| {
+            *event == NetworkEvent::StateChanged(PeerState::Connected)
+        });
+        recv_until(&peer2, |event| {
+            *event == NetworkEvent::StateChanged(PeerState::Connected)
+        });
+
+        let id1 = peer1.my_id().unwrap();
+        peer1.disconnect();
+        wait_until(|| peer1.state() == PeerState::Disconnected(DisconnectReason::Left));
+        let left = NetworkEvent::PeerDisconnected {
+            id: id1,
+            reason: DisconnectReason::Left,
+        };
+        recv_until(&host, |event| *event == left);
+        recv_until(&peer2, |event| *event == left);
+
+        host.disconnect();
+        let peer2_events = recv_until(&peer2, |event| {
+            *event
+                == NetworkEvent::PeerDisconnected {
+                    id: PeerId(0),
+                    reason: DisconnectReason::HostShutdown,
+                }
+        });
+        assert!(
+            peer2_events.contains(&NetworkEvent::StateChanged(PeerState::Disconnected(
+                DisconnectReason::HostShutdown
+            )))
+        );
+        assert!(matches!(
+            peer2.state(),
+            PeerState::Disconnected(DisconnectReason::HostShutdown)
+        ));
+        // The host says goodbye before it updates its own state.
+        wait_until(|| host.state() == PeerState::Disconnected(DisconnectReason::HostShutdown));
+    }
+
+    #[test_log::test]
+    fn test_kick_ban() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = local_addr(&host);
+        let peer1 = Peer::connect_blocking(addr, None, TIMEOUT).unwrap();
+        let peer2 = Peer::connect_blocking(addr, None, TIMEOUT).unwrap();
+        assert!(matches!(
+            peer1.kick(PeerId(0), "no"),
+            Err(NetError::NotHost)
+        ));
+        assert!(matches!(
+            host.kick(PeerId(0), "no"),
+            Err(NetError::UnknownPeer)
+        ));
+
+        let id1 = peer1.my_id().unwrap();
+        host.kick(id1, "spam").unwrap();
+        let kicked = DisconnectReason::Kicked("spam".to_owned());
+        wait_until(|| peer1.state() == PeerState::Disconnected(kicked.clone()));
+        recv_until(&host, |event| {
+            *event
+                == NetworkEvent::PeerDisconnected {
+                    id: id1,
+                    reason: kicked.clone(),
+                }
+        });
+
+        host.ban(peer2.my_id().unwrap(), "cheating").unwrap();
+        let banned = DisconnectReason::Banned("cheating".to_owned());
+        wait_until(|| peer2.state() == PeerState::Disconnected(banned.clone()));
+        let localhost = addr.ip();
+        assert_eq!(host.bans(), vec![localhost]);
+        assert!(matches!(
+            Peer::connect_blocking(addr, None, TIMEOUT),
+            Err(ConnectError::Rejected(DisconnectReason::Banned(_)))
+        ));
+        assert_eq!(host.iter_peer_ids().count(), 1);
+
+        let file = TempFile::new("kick_ban");
+        let path = &file.0;
+        host.save_bans(path).unwrap();
+        assert!(host.unban(localhost));
+        assert!(!host.unban(localhost));
+        assert!(host.bans().is_empty());
+        host.load_bans(path).unwrap();
+        assert_eq!(host.bans(), vec![localhost]);
+    }
+
+    #[test_log::test]
+    fn test_identity_ban() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = local_addr(&host);
+        let connect = |payload: &[u8]| {
+            let peer = Peer::connect_with_payload(addr, payload.to_vec(), None).unwrap();
+            peer.wait_connected(TIMEOUT).map(|_| peer)
+        };
+        let alice = connect(b"alice").unwrap();
+        host.ban(alice.my_id().unwrap(), "cheating").unwrap();
+        wait_until(|| matches!(alice.state(), PeerState::Disconnected(_)));
+        assert_eq!(host.identity_bans(), vec![b"alice".to_vec()]);
+
+        // Still banned once the address isn't.
+        assert!(host.unban(addr.ip()));
+        assert!(matches!(
+            connect(b"alice"),
+            Err(ConnectError::Rejected(DisconnectReason::Banned(_)))
+        ));
+        assert!(connect(b"bob").is_ok());
+        host.ban_identity(*b"mallory");
+        assert!(connect(b"mallory").is_err());
+
+        let file = TempFile::new("identity_ban");
+        let path = &file.0;
+        host.save_bans(path).unwrap();
+        assert!(host.unban_identity(b"alice"));
+        assert!(host.unban_identity(b"mallory"));
+        assert!(!host.unban_identity(b"mallory"));
+        host.load_bans(path).unwrap();
+        let mut identities = host.identity_bans();
+        identities.sort();
+        assert_eq!(identities, vec![b"alice".to_vec(), b"mallory".to_vec()]);
+    }
+
+    #[test_log::test]
+    fn test_connection_request() {
+        let settings = Some(Settings {
+            manual_accept: true,
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings.clone()).unwrap();
+        let addr = local_addr(&host);
+        let alice = Peer::connect_with_payload(addr, b"alice".to_vec(), settings.clone()).unwrap();
+        let mallory = Peer::connect_with_payload(addr, b"mallory".to_vec(), settings).unwrap();
+        let mut requests = Vec::new();
+        recv_until(&host, |event| {
+            if let NetworkEvent::ConnectionRequest { addr, payload } = event {
+                requests.push((payload.clone(), *addr));
+            }
+            requests.len() == 2
+        });
+        requests.sort();
+        assert!(matches!(alice.state(), PeerState::PendingConnection));
+        assert_eq!(host.iter_peer_ids().count(), 1);
+        let (_, alice_addr) = requests[0];
+        let (_, mallory_addr) = requests[1];
+        assert_eq!(requests[0].0, b"alice");
+        assert!(matches!(alice.accept(alice_addr), Err(NetError::NotHost)));
+
+        host.accept(alice_addr).unwrap();
+        host.reject(mallory_addr, "go away").unwrap();
+        assert!(alice.wait_connected(TIMEOUT).is_ok());
+        assert!(matches!(alice.state(), PeerState::Connected));
+        assert_eq!(
+            mallory.wait_connected(TIMEOUT),
+            Err(ConnectError::Rejected(DisconnectReason::Rejected(
+                "go away".to_owned()
+            )))
+        );
+        assert_eq!(host.iter_peer_ids().count(), 2);
+    }
+
+    #[test_log::test]
+    fn test_connection_request_expired() {
+        let settings = Some(Settings {
+            manual_accept: true,
+            connection_timeout: Duration::from_millis(200),
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings).unwrap();
+        let peer = Peer::connect(local_addr(&host), None).unwrap();
+        recv_until(&host, |event| {
+            matches!(event, NetworkEvent::ConnectionRequest { .. })
+        });
+        assert_eq!(
+            peer.wait_connected(TIMEOUT),
+            Err(ConnectError::Rejected(DisconnectReason::Rejected(
+                "Not accepted in time".to_owned()
+            )))
+        );
+    }
+
+    #[test_log::test]
+    fn test_connect_retry() {
+        // Nothing answers the first login.
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        silent.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let addr = silent.local_addr().unwrap();
+        let peer = Peer::connect(addr, None).unwrap();
+        silent.recv_from(&mut [0; 2048]).unwrap();
+        assert!(matches!(peer.state(), PeerState::PendingConnection));
+        drop(silent);
+        let _host = Peer::host(addr, None).unwrap();
+        assert!(peer.wait_connected(TIMEOUT).is_ok());
+    }
+
+    #[test_log::test]
+    fn test_connect_failed() {
+        let settings = Some(Settings {
+            connect_timeout: Duration::from_millis(300),
+            ..Default::default()
+        });
+        // Nothing answers there.
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let peer = Peer::connect(silent.local_addr().unwrap(), settings).unwrap();
+        assert_eq!(peer.wait_connected(TIMEOUT), Err(ConnectError::Unreachable));
+        assert_eq!(
+            peer.recv().collect::<Vec<_>>(),
+            vec![NetworkEvent::StateChanged(PeerState::ConnectFailed(
+                ConnectError::Unreachable
+            ))]
+        );
+
+        // Something that isn't a tangled host answers.
+        let fake_host = UdpSocket::bind("127.0.0.1:0").unwrap();
+        fake_host.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let peer = Peer::connect(fake_host.local_addr().unwrap(), None).unwrap();
+        let mut buf = [0; 2048];
+        let (_, peer_addr) = fake_host.recv_from(&mut buf).unwrap();
+        fake_host.send_to(b"nonsense", peer_addr).unwrap();
+        // A single one may have been corrupted on the way, so logging in goes on.
+        fake_host.recv_from(&mut buf).unwrap();
+        assert_eq!(peer.state(), PeerState::PendingConnection);
+        for _ in 0..10 {
+            fake_host.send_to(b"nonsense", peer_addr).unwrap();
+        }
+        assert_eq!(
+            peer.wait_connected(TIMEOUT),
+            Err(ConnectError::ProtocolMismatch)
+        );
+    }
+
+    /// Relays datagrams between a single client and `host_addr`, dropping everything while `blocked` is set.
+    /// Returns the address of the relay.
+    fn flaky_relay(host_addr: SocketAddr, blocked: Arc<AtomicBool>) -> SocketAddr {
+        relay(host_addr, blocked, usize::MAX)
+    }
+
+    /// Relay between a host and a single client that drops datagrams longer than `max_len`, like a path with a smaller MTU would.
+    fn relay(host_addr: SocketAddr, blocked: Arc<AtomicBool>, max_len: usize) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 65536];
+            let mut client_addr = None;
+            while let Ok((len, from)) = socket.recv_from(&mut buf) {
+                let to = if from == host_addr {
+                    client_addr
+                } else {
+                    client_addr = Some(from);
+                    Some(host_addr)
+                };
+                if let Some(to) = to.filter(|_| !blocked.load(SeqCst) && len <= max_len) {
+                    socket.send_to(&buf[..len], to).ok();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test_log::test]
+    fn test_mtu_discovery_limited() {
+        let settings = Some(Settings {
+            mtu_discovery: true,
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings.clone()).unwrap();
+        let relay_addr = relay(local_addr(&host), Arc::new(AtomicBool::new(false)), 4000);
+        let peer = Peer::connect_blocking(relay_addr, settings, TIMEOUT).unwrap();
+        // Probes that don't get through are given up on sooner once the round-trip time is known.
+        let handle = peer
+            .send(PeerId(0), vec![0], Reliability::Reliable)
+            .unwrap();
+        recv_until(&peer, |event| *event == NetworkEvent::Delivered(handle));
+        recv_messages(&host, 1);
+        wait_until(|| peer.mtu(PeerId(0)).is_some_and(|mtu| mtu > 1400));
+        let data: Vec<u8> = (0..30_000).map(|i| i as u8).collect();
+        peer.send(PeerId(0), data.clone(), Reliability::ReliableOrdered)
+            .unwrap();
+        assert_eq!(recv_messages(&host, 1)[0].data, data);
+        assert!(peer.mtu(PeerId(0)).unwrap() <= 4000);
+    }
+
+    #[test_log::test]
+    fn test_session_resume() {
+        let settings = Some(Settings {
+            confirm_max_period: Duration::from_millis(50),
+            connection_timeout: Duration::from_millis(300),
+            resume_grace_period: Some(Duration::from_millis(1000)),
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings.clone()).unwrap();
+        let addr = local_addr(&host);
+        let blocked = Arc::new(AtomicBool::new(false));
+        let relay_addr = flaky_relay(addr, blocked.clone());
+        let flaky = Peer::connect_blocking(relay_addr, settings.clone(), TIMEOUT).unwrap();
+        let other = Peer::connect_blocking(addr, settings, TIMEOUT).unwrap();
+        let id = flaky.my_id().unwrap();
+
+        blocked.store(true, SeqCst);
+        recv_until(&flaky, |event| {
+            *event == NetworkEvent::PeerReconnecting(PeerId(0))
+        });
+        wait_until(|| flaky.state() == PeerState::Reconnecting);
+        recv_until(&host, |event| *event == NetworkEvent::PeerReconnecting(id));
+        recv_until(&other, |event| *event == NetworkEvent::PeerReconnecting(id));
+        let data = vec![1, 2, 3];
+        host.send(id, data.clone(), Reliability::Reliable).unwrap();
+
+        blocked.store(false, SeqCst);
+        let message = NetworkEvent::Message(Message {
+            channel: ChannelId::default(),
+            data,
+        });
+        let (mut resumed, mut received) = (false, false);
+        recv_until(&flaky, |event| {
+            resumed |= *event == NetworkEvent::PeerResumed(PeerId(0));
+            received |= *event == message;
+            resumed && received
+        });
+        wait_until(|| flaky.state() == PeerState::Connected);
+        assert_eq!(flaky.my_id(), Some(id));
+        let other_events = recv_until(&other, |event| *event == NetworkEvent::PeerResumed(id));
+        assert!(!other_events
+            .iter()
+            .any(|event| matches!(event, NetworkEvent::PeerDisconnected { .. })));
+
+        // Not back within the grace period.
+        blocked.store(true, SeqCst);
+        recv_until(&host, |event| {
+            *event
+                == NetworkEvent::PeerDisconnected {
+                    id,
+                    reason: DisconnectReason::Timeout,
+                }
+        });
+        wait_until(|| flaky.state() == PeerState::Disconnected(DisconnectReason::Timeout));
+    }
+
+    #[test_log::test]
+    fn test_session_expired() {
+        let host_settings = Settings {
+            connection_timeout: Duration::from_millis(200),
+            resume_grace_period: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        // Keeps trying to resume after the host has given up on it.
+        let client_settings = Settings {
+            resume_grace_period: Some(Duration::from_secs(10)),
+            ..host_settings.clone()
+        };
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), Some(host_settings)).unwrap();
+        let blocked = Arc::new(AtomicBool::new(false));
+        let relay_addr = flaky_relay(local_addr(&host), blocked.clone());
+        let flaky = Peer::connect_blocking(relay_addr, Some(client_settings), TIMEOUT).unwrap();
+        let id = flaky.my_id().unwrap();
+
+        blocked.store(true, SeqCst);
+        recv_until(&host, |event| {
+            *event
+                == NetworkEvent::PeerDisconnected {
+                    id,
+                    reason: DisconnectReason::Timeout,
+                }
+        });
+        blocked.store(false, SeqCst);
+        wait_until(|| flaky.state() == PeerState::Disconnected(DisconnectReason::SessionExpired));
+    }
+
+    #[test_log::test]
+    fn test_peer_stats() {
+        let settings = Some(Settings {
+            ping_interval: Some(Duration::from_millis(50)),
+            latency_events: true,
+            ..Default::default()
+        });
+        let (host, peer) = host_and_client(settings);
+        let id = peer.my_id().unwrap();
+        let mut updates = 0;
+        recv_until(&host, |event| {
+            updates += matches!(event, NetworkEvent::LatencyUpdate { id: from, .. } if *from == id)
+                as usize;
+            updates == 3
+        });
+        assert!(host.peer_stats(id).is_some());
+        wait_until(|| peer.peer_stats(PeerId(0)).is_some());
+        let stats = peer.peer_stats(PeerId(0)).unwrap();
+        // Way more than loopback ever takes, only rules out nonsense.
+        assert!(stats.rtt < Duration::from_secs(1));
+        // Both report the same one, which a ping may update in between the two calls.
+        wait_until(|| peer.peer_stats(PeerId(0)).map(|stats| stats.rtt) == peer.rtt(PeerId(0)));
+        // Both sides use the same clock.
+        assert!(stats.clock_offset.abs() < 1_000_000);
+        assert!(peer.peer_stats(id).is_none());
+    }
+
+    #[test_log::test]
+    fn test_connect_blocking() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        assert_eq!(host.wait_connected(Duration::ZERO).unwrap(), PeerId(0));
+        let peer = Peer::connect_blocking(local_addr(&host), None, Duration::from_secs(1)).unwrap();
+        let id = peer.my_id().unwrap();
+        assert_eq!(peer.wait_connected(Duration::ZERO).unwrap(), id);
+
+        // Nothing answers there.
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = silent.local_addr().unwrap();
+        assert_eq!(
+            Peer::connect_blocking(addr, None, Duration::from_millis(100)).err(),
+            Some(ConnectError::Timeout)
+        );
+        let settings = Some(Settings {
+            connect_timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let started = Instant::now();
+        assert_eq!(
+            Peer::connect_blocking(addr, settings, Duration::from_secs(5)).err(),
+            Some(ConnectError::Unreachable)
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test_log::test]
+    fn test_shutdown() {
+        let (host, peer) = host_and_client(None);
+        let addr = local_addr(&host);
+        let peer_addr = local_addr(&peer);
+        for i in 0..100 {
+            peer.send(PeerId(0), vec![i], Reliability::Reliable)
+                .unwrap();
+        }
+        let started = Instant::now();
+        peer.shutdown(Duration::from_secs(1));
+        // Returns as soon as everything is confirmed, instead of waiting for the timeout.
+        assert!(started.elapsed() < Duration::from_secs(1));
+        let received = host
+            .recv()
+            .filter(|event| matches!(event, NetworkEvent::Message(_)))
+            .count();
+        assert_eq!(received, 100);
+
+        let host_clone = host.clone();
+        host.shutdown(Duration::from_secs(1));
+        assert!(matches!(
+            host_clone.send(PeerId(1), vec![], Reliability::Unreliable),
+            Err(NetError::Disconnected)
+        ));
+        drop(host_clone);
+        UdpSocket::bind(addr).unwrap();
+        UdpSocket::bind(peer_addr).unwrap();
+    }
+
+    /// Endless stream that notes when the thread reading it has let go of it.
+    struct EndlessReader(Arc<AtomicBool>);
+
+    impl io::Read for EndlessReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            thread::sleep(Duration::from_millis(1));
+            buf.fill(0);
+            Ok(buf.len())
+        }
+    }
+
+    impl Drop for EndlessReader {
+        fn drop(&mut self) {
+            self.0.store(true, SeqCst);
+        }
+    }
+
+    #[test_log::test]
+    fn test_drop_joins_threads() {
+        let (host, peer) = host_and_client(None);
+        let peer_addr = local_addr(&peer);
+        let dropped = Arc::new(AtomicBool::new(false));
+        peer.send_stream(PeerId(0), EndlessReader(dropped.clone()))
+            .unwrap();
+        recv_until(&host, |event| {
+            matches!(event, NetworkEvent::TransferStarted(_))
+        });
+        drop(peer);
+        // Stopped by the time the drop returns, both the networking threads and the one reading the stream.
+        assert!(dropped.load(SeqCst));
+        UdpSocket::bind(peer_addr).unwrap();
+    }
+
+    #[test_log::test]
+    fn test_channels() {
+        let (host, peer) = host_and_client(None);
+        for i in 0..16 {
+            peer.send_on(
+                ChannelId(3),
+                PeerId(0),
+                vec![i],
+                Reliability::ReliableOrdered,
+            )
+            .unwrap();
+        }
+        let received = recv_messages(&host, 16);
+        assert!(received.iter().all(|msg| msg.channel == ChannelId(3)));
+        let data: Vec<_> = received.into_iter().flat_map(|msg| msg.data).collect();
+        assert_eq!(data, (0..16).collect::<Vec<_>>());
+    }
+
+    #[test_log::test]
+    fn test_coalescing() {
+        let (host, peer) = host_and_client(None);
+        // Way more than the rate limit allows to send in separate datagrams.
+        for i in 0..1000u16 {
+            peer.send(
+                PeerId(0),
+                i.to_le_bytes().to_vec(),
+                Reliability::ReliableOrdered,
+            )
+            .unwrap();
+        }
+        let received: Vec<_> = recv_messages(&host, 1000)
+            .into_iter()
+            .map(|msg| u16::from_le_bytes([msg.data[0], msg.data[1]]))
+            .collect();
+        assert_eq!(received, (0..1000).collect::<Vec<_>>());
+    }
+
+    #[test_log::test]
+    fn test_download_limit() {
+        let settings = Some(Settings {
+            max_download_rate: Some(20_000),
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings).unwrap();
+        // The limit is sent along with the registration.
+        let peer = Peer::connect_blocking(local_addr(&host), None, TIMEOUT).unwrap();
+        let started = Instant::now();
+        for _ in 0..20 {
+            peer.send(PeerId(0), vec![0; 1000], Reliability::Reliable)
+                .unwrap();
+        }
+        recv_messages(&host, 20);
+        // Only a short burst goes out right away, sending the rest at this rate takes over 800 ms.
+        assert!(started.elapsed() > Duration::from_millis(500));
+    }
+
+    #[test_log::test]
+    fn test_upload_limit() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let settings = Some(Settings {
+            max_upload_rate: Some(20_000),
+            ..Default::default()
+        });
+        let peer = Peer::connect_blocking(local_addr(&host), settings, TIMEOUT).unwrap();
+        let started = Instant::now();
+        for _ in 0..20 {
+            peer.send(PeerId(0), vec![0; 1000], Reliability::Reliable)
+                .unwrap();
+        }
+        recv_messages(&host, 20);
+        assert!(started.elapsed() > Duration::from_millis(500));
+    }
+
+    #[test_log::test]
+    fn test_total_upload_limit() {
+        let settings = Some(Settings {
+            max_total_upload_rate: Some(20_000),
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings).unwrap();
+        let peers: Vec<_> = (0..2)
+            .map(|_| Peer::connect_blocking(local_addr(&host), None, TIMEOUT).unwrap())
+            .collect();
+        let started = Instant::now();
+        for peer in &peers {
+            for _ in 0..10 {
+                host.send(peer.my_id().unwrap(), vec![0; 1000], Reliability::Reliable)
+                    .unwrap();
+            }
+        }
+        for peer in &peers {
+            recv_messages(peer, 10);
+        }
+        // Each peer alone would be well within the limit.
+        assert!(started.elapsed() > Duration::from_millis(500));
+    }
+
+    #[test_log::test]
+    fn test_total_download_limit() {
+        let settings = Some(Settings {
+            max_total_download_rate: Some(20_000),
+            ..Default::default()
+        });
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings).unwrap();
+        let peers: Vec<_> = (0..2)
+            .map(|_| Peer::connect_blocking(local_addr(&host), None, TIMEOUT).unwrap())
+            .collect();
+        for peer in &peers {
+            // Shares are announced in order with this, so the peer knows its latest one after receiving it.
+            host.send(peer.my_id().unwrap(), vec![], Reliability::ReliableOrdered)
+                .unwrap();
+            recv_messages(peer, 1);
+        }
+        let started = Instant::now();
+        for peer in &peers {
+            for _ in 0..10 {
+                peer.send(PeerId(0), vec![0; 1000], Reliability::Reliable)
+                    .unwrap();
+            }
+        }
+        recv_messages(&host, 20);
+        // With half of the limit each, sending the rest after the first burst takes over 800 ms.
+        assert!(started.elapsed() > Duration::from_millis(700));
+    }
+
+    #[test_log::test]
+    fn test_mtu_discovery() {
+        let settings = Some(Settings {
+            mtu_discovery: true,
+            ..Default::default()
+        });
+        let (host, peer) = host_and_client(settings);
+        // Loopback has a huge MTU.
+        wait_until(|| peer.mtu(PeerId(0)).is_some_and(|mtu| mtu > 10_000));
+        let data: Vec<u8> = (0..30_000).map(|i| i as u8).collect();
+        peer.send(PeerId(0), data.clone(), Reliability::Reliable)
+            .unwrap();
+        assert_eq!(
+            recv_messages(&host, 1),
+            vec![Message {
+                channel: ChannelId::default(),
+                data
+            }]
+        );
+    }
+
+    #[test_log::test]
+    fn test_fragmentation() {
+        let settings = Some(Settings {
+            max_message_len: 8000,
+            ..Default::default()
+        });
+        let (host, peer) = host_and_client(settings);
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        peer.send(PeerId(0), data.clone(), Reliability::Reliable)
+            .unwrap();
+        assert!(matches!(
+            peer.send(PeerId(0), vec![0; 8001], Reliability::Reliable),
+            Err(NetError::MessageTooLong)
+        ));
+        assert_eq!(
+            recv_messages(&host, 1),
+            vec![Message {
+                channel: ChannelId::default(),
+                data
+            }]
+        );
+    }
+
+    #[test_log::test]
+    fn test_fragment_buffer() {
+        let settings = Some(Settings {
+            max_message_len: 8000,
+            max_fragment_buffer: 4000,
+            ..Default::default()
+        });
+        let (host, peer) = host_and_client(settings);
+        peer.send(PeerId(0), vec![1; 5000], Reliability::Reliable)
+            .unwrap();
+        // Sent after all the fragments, so they have been handled once it arrives.
+        peer.send(PeerId(0), vec![2], Reliability::Reliable)
+            .unwrap();
+        let received = recv_messages(&host, 1);
+        assert_eq!(received[0].data, vec![2]);
+        assert!(!host
+            .recv()
+            .any(|event| matches!(event, NetworkEvent::Message(_))));
+    }
+
+    #[test_log::test]
+    fn test_stream() {
+        let (host, peer) = host_and_client(None);
+        let data: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+        let id = peer
+            .send_stream(PeerId(0), io::Cursor::new(data.clone()))
+            .unwrap();
+        let mut started = false;
+        let mut progress = Vec::new();
+        let events = recv_until(&host, |event| match event {
+            NetworkEvent::TransferStarted(started_id) => {
+                assert_eq!(*started_id, id);
+                started = true;
+                false
+            }
+            NetworkEvent::TransferProgress {
+                id: progress_id,
+                received,
+            } => {
+                assert_eq!(*progress_id, id);
+                progress.push(*received);
+                false
+            }
+            NetworkEvent::TransferCompleted { .. } => true,
+            NetworkEvent::TransferFailed(_) => panic!("Transfer failed"),
+            _ => false,
+        });
+        assert!(started);
+        // Reported a few times along the way, up to the whole length.
+        assert!(progress.len() > 1);
+        assert!(progress.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!(progress.last(), Some(&(data.len() as u64)));
+        let Some(NetworkEvent::TransferCompleted {
+            id: completed_id,
+            data: received,
+        }) = events.last()
+        else {
+            unreachable!()
+        };
+        assert_eq!(*completed_id, id);
+        assert!(*received == data);
+    }
+
+    /// Blocks until data is sent to it, and ends once the sender is dropped.
+    struct ChannelReader(Receiver<Vec<u8>>);
+
+    impl io::Read for ChannelReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Ok(data) = self.0.recv() else {
+                return Ok(0);
+            };
+            buf[..data.len()].copy_from_slice(&data);
+            Ok(data.len())
+        }
+    }
+
+    #[test_log::test]
+    fn test_stream_slow_reader() {
+        let (host, peer) = host_and_client(None);
+        let (data_s, data_r) = unbounded();
+        let id = peer.send_stream(PeerId(0), ChannelReader(data_r)).unwrap();
+        // Waiting for the stream doesn't hold up other messages.
+        peer.send(PeerId(0), vec![1], Reliability::Reliable)
+            .unwrap();
+        assert_eq!(recv_messages(&host, 1)[0].data, vec![1]);
+        data_s.send(vec![2; 100]).unwrap();
+        drop(data_s);
+        let events = recv_until(&host, |event| {
+            matches!(
+                event,
+                NetworkEvent::TransferCompleted { .. } | NetworkEvent::TransferFailed(_)
+            )
+        });
+        assert_eq!(
+            events.last(),
+            Some(&NetworkEvent::TransferCompleted {
+                id,
+                data: vec![2; 100]
+            })
+        );
+    }
+
+    #[test_log::test]
+    fn test_stream_cancel() {
+        let (host, peer) = host_and_client(None);
+        let (data_s, data_r) = unbounded();
+        let id = peer.send_stream(PeerId(0), ChannelReader(data_r)).unwrap();
+        data_s.send(vec![1; 100]).unwrap();
+        recv_until(&host, |event| *event == NetworkEvent::TransferStarted(id));
+
+        // The receiver gives up on it, which the sender is told about.
+        host.cancel_transfer(id).unwrap();
+        let failed = |event: &NetworkEvent| match event {
+            NetworkEvent::TransferFailed(failed_id) => {
+                assert_eq!(*failed_id, id);
+                true
+            }
+            NetworkEvent::TransferCompleted { .. } => panic!("Cancelled transfer completed"),
+            _ => false,
+        };
+        recv_until(&host, failed);
+        recv_until(&peer, failed);
+        // Anything still read from the stream is no longer sent.
+        data_s.send(vec![2; 100]).ok();
+        drop(data_s);
+        peer.send(PeerId(0), vec![3], Reliability::Reliable)
+            .unwrap();
+        let events = recv_until(&host, |event| matches!(event, NetworkEvent::Message(_)));
+        assert!(events.iter().all(|event| !matches!(
+            event,
+            NetworkEvent::TransferStarted(_) | NetworkEvent::TransferProgress { .. }
+        )));
+    }
+
+    #[test_log::test]
+    fn test_deadline() {
+        let (_host, peer) = host_and_client(None);
+        let options = SendOptions {
+            deadline: Some(Instant::now()),
+            ..Default::default()
+        };
+        let handle = peer
+            .send_with(PeerId(0), vec![1, 2, 3], Reliability::Reliable, options)
+            .unwrap();
+        let peer_events = recv_until(&peer, |event| {
+            matches!(event, NetworkEvent::Lost(_) | NetworkEvent::Delivered(_))
+        });
+        assert_eq!(peer_events.last(), Some(&NetworkEvent::Lost(handle)));
+    }
+
+    /// Hosts on a free port and connects a client to it through a `flaky_relay`, returning the flag that blocks the relay.
+    /// The client has measured the round-trip time by then, so that it doesn't wait long before resending.
+    fn host_and_relayed_client(settings: Option<Settings>) -> (Peer, Peer, Arc<AtomicBool>) {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), settings.clone()).unwrap();
+        let blocked = Arc::new(AtomicBool::new(false));
+        let relay_addr = flaky_relay(local_addr(&host), blocked.clone());
+        let client = Peer::connect_blocking(relay_addr, settings, TIMEOUT).unwrap();
+        let handle = client
+            .send(PeerId(0), vec![0], Reliability::Reliable)
+            .unwrap();
+        recv_until(&client, |event| *event == NetworkEvent::Delivered(handle));
+        recv_messages(&host, 1);
+        (host, client, blocked)
+    }
+
+    /// Sends a reliable message while the relay is blocked, checking that it ends up lost.
+    fn send_blocked(peer: &Peer, blocked: &AtomicBool, options: SendOptions) {
+        blocked.store(true, SeqCst);
+        let handle = peer
+            .send_with(PeerId(0), vec![1], Reliability::Reliable, options)
+            .unwrap();
+        let events = recv_until(peer, |event| {
+            matches!(event, NetworkEvent::Lost(_) | NetworkEvent::Delivered(_))
+        });
+        blocked.store(false, SeqCst);
+        assert_eq!(events.last(), Some(&NetworkEvent::Lost(handle)));
+    }
+
+    #[test_log::test]
+    fn test_deadline_resend() {
+        let (host, peer, blocked) = host_and_relayed_client(None);
+        let options = SendOptions {
+            deadline: Some(Instant::now() + Duration::from_millis(200)),
+            ..Default::default()
+        };
+        send_blocked(&peer, &blocked, options);
+        // Given up on, so it isn't resent once the link is back.
+        peer.send(PeerId(0), vec![2], Reliability::Reliable)
+            .unwrap();
+        assert_eq!(recv_messages(&host, 1)[0].data, vec![2]);
+    }
+
+    #[test_log::test]
+    fn test_max_resends() {
+        let settings = Settings {
+            max_resends: Some(2),
+            ..Default::default()
+        };
+        let (host, peer, blocked) = host_and_relayed_client(Some(settings));
+        send_blocked(&peer, &blocked, SendOptions::default());
+        peer.send(PeerId(0), vec![2], Reliability::Reliable)
+            .unwrap();
+        assert_eq!(recv_messages(&host, 1)[0].data, vec![2]);
+    }
+
+    #[test_log::test]
+    fn test_max_message_age() {
+        let settings = Settings {
+            max_message_age: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let (host, peer, blocked) = host_and_relayed_client(Some(settings));
+        send_blocked(&peer, &blocked, SendOptions::default());
+        peer.send(PeerId(0), vec![2], Reliability::Reliable)
+            .unwrap();
+        assert_eq!(recv_messages(&host, 1)[0].data, vec![2]);
+    }
+
+    #[test_log::test]
+    fn test_confirm_burst() {
+        let (host, peer) = host_and_client(None);
+        // More messages than a single ack can confirm.
+        let handles: Vec<_> = (0..100)
+            .map(|i| {
+                peer.send(PeerId(0), vec![i], Reliability::Reliable)
+                    .unwrap()
+            })
+            .collect();
+        let mut delivered = 0;
+        recv_until(&peer, |event| {
+            if let NetworkEvent::Delivered(handle) = event {
+                assert!(handles.contains(handle));
+                delivered += 1;
+            }
+            delivered == handles.len()
+        });
+        // Confirmed only after being handed over.
+        let received = host
+            .recv()
+            .filter(|event| matches!(event, NetworkEvent::Message(_)))
+            .count();
+        assert_eq!(received, handles.len());
+    }
+
+    #[test_log::test]
+    fn test_resend_after_many() {
+        let (host, peer, blocked) = host_and_relayed_client(None);
+        blocked.store(true, SeqCst);
+        let handle = peer
+            .send(PeerId(0), b"IMPORTANT".to_vec(), Reliability::Reliable)
+            .unwrap();
+        // Long enough for the resends to back off.
+        thread::sleep(Duration::from_millis(500));
+        blocked.store(false, SeqCst);
+        // More messages than the other side keeps track of, which mustn't make the resend look like a duplicate.
+        for i in 0..1500u16 {
+            while let Err(NetError::Dropped) =
+                peer.send(PeerId(0), i.to_le_bytes().to_vec(), Reliability::Unreliable)
+            {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+        recv_until(&peer, |event| *event == NetworkEvent::Delivered(handle));
+        recv_until(
+            &host,
+            |event| matches!(event, NetworkEvent::Message(msg) if msg.data == b"IMPORTANT"),
         );
-        assert_eq!(host.shared.remote_peers.len(), 1);
     }
 }
@@ -1,34 +1,105 @@
 use crate::{
-    error::NetError, util::{RateLimiter, RingSet}, Channel, Message, NetworkEvent, OutboundMessage, PeerId, SeqId
+    error::{ConnectError, NetError}, transfer::{IncomingTransfer, OutgoingTransfer, TransferPart}, util::{
+        join_until, random_u64, seq_newer, unix_micros, ByteBudget, CongestionControl, LatencyTracker, MtuProber, ReorderBuffer, RingSet, RttEstimator, SeqWindow
+    }, Channel, ChannelId, Command, DisconnectReason, Message, MessageHandle, NetworkEvent, OutboundMessage, PeerId, PeerStats, SeqId, TransferId
 };
 
-use super::{Datagram, PeerState, DATAGRAM_MAX_LEN};
+use super::{Datagram, PeerState, DATAGRAM_DEFAULT_LEN, DATAGRAM_MAX_LEN, MAX_MESSAGE_LEN};
 use crossbeam::{
     atomic::AtomicCell, channel::{bounded, Receiver, Sender}, select
 };
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, VecDeque}, error::Error, io::Cursor, net::{SocketAddr, UdpSocket}, sync::{
-        atomic::{AtomicBool, AtomicU16, Ordering::SeqCst}, Arc
-    }, thread, time::{Duration, Instant}
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque}, error::Error, io::ErrorKind, mem::{self, size_of}, net::{IpAddr, SocketAddr, UdpSocket}, sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering::SeqCst}, Arc, Condvar, Mutex
+    }, thread::{self, JoinHandle}, time::{Duration, Instant}
 };
 use tracing::{error, info, trace, warn};
 
 /// Per-peer settings. Peers that are connected to the same host, as well as the host itself, should have the same settings.
 #[derive(Debug, Clone)]
 pub struct Settings {
-    /// A single datagram will confirm at most this much messages. Default is 128.
+    /// Messages that can't be confirmed by the ack of a datagram anymore are confirmed by a separate message, which confirms at most this much of them. Default is 128.
     pub confirm_max_per_message: usize,
-    /// How much time can elapse before another confirm is sent.
-    /// Confirms are also sent when enough messages are awaiting confirm.
-    /// Note that confirms also double as "heartbeats" and keep the connection alive, so this value should be much less than `connection_timeout`.
+    /// Every datagram confirms the messages recently received from its destination.
+    /// When there is nothing else to send, a datagram with just the confirms is sent after this much time.
+    /// Note that these datagrams also double as "heartbeats" and keep the connection alive, so this value should be much less than `connection_timeout`.
     /// Default: 1 second.
     pub confirm_max_period: Duration,
+    /// Confirms are sent at most this much time after a reliable message has been received.
+    /// Lower values make round-trip time estimates, and therefore resends, more precise.
+    /// Default: 10 milliseconds.
+    pub confirm_delay: Duration,
     /// Peers will be disconnected after this much time without any datagrams from them has passed.
     /// Default: 1 second.
     pub connection_timeout: Duration,
+    /// A client gives up on connecting if it hasn't been registered by the host in this much time, which is reported as `ConnectError::Unreachable`.
+    /// The login is resent with growing delays until then.
+    /// Default: 10 seconds.
+    pub connect_timeout: Duration,
+    /// Messages longer than `MAX_MESSAGE_LEN` are split into fragments and reassembled on the other side.
+    /// This is the limit on the total length of such a message, both when sending and receiving.
+    /// A message can't be split into more than `u16::MAX` fragments though, so longer messages are rejected regardless.
+    /// Default: 1 MiB.
+    pub max_message_len: usize,
+    /// A partially received fragmented message is discarded if it isn't completed in this much time.
+    /// Default: 10 seconds.
+    pub fragment_timeout: Duration,
+    /// Fragments of partially received messages are buffered up to this much bytes per peer, further ones are ignored.
+    /// `ReliableOrdered` messages waiting for the ones before them count towards it too, further ones are left to be resent.
+    /// Should be a few times larger than `max_message_len`.
+    /// Default: 8 MiB.
+    pub max_fragment_buffer: usize,
+    /// Stream transfers keep at most this much bytes in flight without an acknowledgement from the receiver.
+    /// Default: 256 KiB.
+    pub transfer_window: usize,
+    /// Incoming stream transfers longer than this are cancelled.
+    /// Default: 256 MiB.
+    pub max_transfer_len: u64,
+    /// Reliable messages are given up on after being resent this many times, which is reported by a `Lost` event.
+    /// Doesn't apply to `ReliableOrdered` messages.
+    /// Default: None (no limit).
+    pub max_resends: Option<u32>,
+    /// Messages are dropped if they weren't sent, or confirmed in case of reliable ones, in this much time.
+    /// Doesn't apply to `ReliableOrdered` messages.
+    /// Default: None (no limit).
+    pub max_message_age: Option<Duration>,
+    /// Bytes per second sent to a single peer, including resends and stream transfers.
+    /// Default: None (no limit).
+    pub max_upload_rate: Option<u64>,
+    /// Bytes per second a single peer is asked to send to this one at most.
+    /// The limit is announced to the other side on connection, which then applies it on top of its own `max_upload_rate`.
+    /// Default: None (no limit).
+    pub max_download_rate: Option<u64>,
+    /// Bytes per second sent to all peers together. Mostly useful for the host, which has a connection to every peer.
+    /// Default: None (no limit).
+    pub max_total_upload_rate: Option<u64>,
+    /// Bytes per second all peers together are asked to send to this one at most. Mostly useful for the host.
+    /// Shared equally between the direct peers, which are told their new share whenever a peer joins or leaves.
+    /// Default: None (no limit).
+    pub max_total_download_rate: Option<u64>,
+    /// Probe every direct peer for the largest datagram that gets through, and use it for packing messages and fragmentation.
+    /// Datagrams are sent with the Don't Fragment flag where the platform allows it, so that sizes which only get through as IP fragments aren't used.
+    /// When probes stop getting through, datagrams shrink back to the default size, and fragments made for the larger size are split up further.
+    /// Default: false.
+    pub mtu_discovery: bool,
+    /// Instead of accepting logins right away, the host reports them as `ConnectionRequest` events.
+    /// Each of them has to be answered by `Peer::accept` or `Peer::reject` within `connection_timeout`, otherwise the login is rejected.
+    /// Default: false.
+    pub manual_accept: bool,
+    /// Peers that time out are kept around for this much longer, so that a client can resume its session after a short network outage.
+    /// A resumed client keeps its `PeerId`, and gets the reliable messages sent to it in the meantime.
+    /// Should be the same on the host and the clients.
+    /// Default: None (peers are removed right away).
+    pub resume_grace_period: Option<Duration>,
+    /// Every direct peer is pinged this often, to measure the latency reported by `Peer::peer_stats`.
+    /// Default: 1 second.
+    pub ping_interval: Option<Duration>,
+    /// Report every answered ping as a `LatencyUpdate` event.
+    /// Default: false.
+    pub latency_events: bool,
 }
 
 impl Default for Settings {
@@ -36,7 +107,25 @@ impl Default for Settings {
         Self {
             confirm_max_per_message: 128,
             confirm_max_period: Duration::from_secs(1),
+            confirm_delay: Duration::from_millis(10),
             connection_timeout: Duration::from_secs(10),
+            connect_timeout: Duration::from_secs(10),
+            max_message_len: 1024 * 1024,
+            fragment_timeout: Duration::from_secs(10),
+            max_fragment_buffer: 8 * 1024 * 1024,
+            transfer_window: 256 * 1024,
+            max_transfer_len: 256 * 1024 * 1024,
+            max_resends: None,
+            max_message_age: None,
+            max_upload_rate: None,
+            max_download_rate: None,
+            max_total_upload_rate: None,
+            max_total_download_rate: None,
+            mtu_discovery: false,
+            manual_accept: false,
+            resume_grace_period: None,
+            ping_interval: Some(Duration::from_secs(1)),
+            latency_events: false,
         }
     }
 }
@@ -46,29 +135,126 @@ pub(crate) struct Shared {
     pub socket: UdpSocket,
     pub inbound_channel: Channel<NetworkEvent>,
     pub outbound_channel: Channel<OutboundMessage>,
+    pub command_channel: Channel<Command>,
     pub keep_alive: AtomicBool,
-    pub peer_state: AtomicCell<PeerState>,
+    pub peer_state: Mutex<PeerState>,
+    /// Notified on every change of `peer_state`.
+    pub state_changed: Condvar,
+    /// No more messages are accepted once set, see `Peer::shutdown`.
+    pub shutting_down: AtomicBool,
+    /// Reactor and pipe threads, joined by `Peer::shutdown`.
+    pub threads: Mutex<Option<(JoinHandle<()>, JoinHandle<()>)>>,
+    /// Addresses the host doesn't accept logins from.
+    pub bans: DashSet<IpAddr>,
+    /// Login payloads the host doesn't accept, no matter the address.
+    pub identity_bans: DashSet<Vec<u8>>,
     pub remote_peers: DashMap<PeerId, RemotePeer>,
-    pub max_packets_per_second: usize,
     pub host_addr: Option<SocketAddr>,
     pub my_id: AtomicCell<Option<PeerId>>,
+    /// Sent to the host along with the login.
+    pub login_payload: Vec<u8>,
+    pub transfer_counter: AtomicU32,
+    pub message_counter: AtomicU64,
+}
+
+impl Shared {
+    /// Updates the local state, reporting it with a `StateChanged` event if it's different.
+    pub fn set_state(&self, state: PeerState) {
+        let mut current = self.peer_state.lock().unwrap();
+        if *current != state {
+            *current = state.clone();
+            self.state_changed.notify_all();
+            self.inbound_channel
+                .0
+                .send(NetworkEvent::StateChanged(state))
+                .ok();
+        }
+    }
 }
 
 struct DirectPeer {
     addr: SocketAddr,
     outbound_pending: VecDeque<NetMessageVariant>,
-    resend_pending: VecDeque<(Instant, NetMessageNormal)>,
-    confirmed: RingSet<SeqId>,
-    rate_limit: RateLimiter,
+    bulk_pending: VecDeque<NetMessageNormal>,
+    in_flight: HashSet<SeqId>,
+    congestion: CongestionControl,
+    /// Bulk messages among `in_flight`.
+    bulk_in_flight: HashSet<SeqId>,
+    /// Paces bulk messages, so that they neither take the tokens of other messages nor slow them down when they get lost.
+    bulk_congestion: CongestionControl,
+    upload_budget: Option<ByteBudget>,
+    mtu: MtuProber,
     seq_counter: AtomicU16,
-    recent_seq: RingSet<SeqId>,
-    pending_confirms: VecDeque<SeqId>,
-    last_confirm_sent: Instant,
+    /// Newest sequence id sent, which the `SeqWindow` of the other side may have moved up to.
+    newest_sent: Option<SeqId>,
+    recent_seq: SeqWindow,
+    channels: HashMap<ChannelId, ChannelState>,
+    fragment_counter: u16,
+    fragments: HashMap<u16, PartialMessage>,
+    /// Memory taken by `fragments`, limited by `Settings::max_fragment_buffer`.
+    fragment_bytes: usize,
+    /// Memory taken by messages held back in `ChannelState::ordered_inbound`, limited along with `fragment_bytes`.
+    held_bytes: usize,
+    /// Channel that gets to resend first, so that a busy one can't keep the others from resending.
+    resend_start: usize,
+    receipts: HashMap<SeqId, MessageHandle>,
+    unconfirmed: HashMap<MessageHandle, usize>,
+    unacked_received: u32,
+    /// Received messages that need a confirm, since the last datagram with an ack has been sent.
+    received_since_ack: Vec<SeqId>,
+    /// Received messages that have to be confirmed by a `Confirm` message.
+    late_confirms: Vec<SeqId>,
+    confirm_due: Option<Instant>,
+    last_sent: Instant,
+    sent_at: HashMap<SeqId, Instant>,
+    rtt: RttEstimator,
     last_seen: Instant,
+    latency: LatencyTracker,
+    next_ping: Instant,
+    /// Token of the client's session. Only known to the host.
+    session_token: Option<u64>,
+    /// Payload the client has logged in with. Only known to the host.
+    login_payload: Vec<u8>,
+    /// When the connection has been lost, if the peer is waiting to resume its session.
+    suspended: Option<Instant>,
+}
+
+/// Ordering, sequencing and resend state of a single channel between two direct peers.
+struct ChannelState {
+    resend_pending: VecDeque<PendingResend>,
+    order_counter: SeqId,
+    ordered_inbound: ReorderBuffer<NetMessageNormal>,
+    sequence_counter: SeqId,
+    last_sequenced: Option<SeqId>,
+}
+
+/// A reliable message waiting for a confirm.
+struct PendingResend {
+    at: Instant,
+    resends: u32,
+    msg: NetMessageNormal,
+}
+
+/// Fragments of a message that has not been fully received yet.
+struct PartialMessage {
+    started: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    remaining: usize,
+    /// Bytes counted towards `DirectPeer::fragment_bytes`.
+    size: usize,
 }
 
 #[derive(Default)]
-pub struct RemotePeer {}
+pub struct RemotePeer {
+    /// Smoothed round-trip time, only known for direct peers.
+    pub rtt: Option<Duration>,
+    /// Packets per second allowed by congestion control, only known for direct peers.
+    pub send_rate: Option<f64>,
+    /// Largest datagram known to get through, only known for direct peers.
+    pub mtu: Option<usize>,
+    /// Measured by pings, only known for direct peers.
+    pub stats: Option<PeerStats>,
+}
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub enum Destination {
@@ -78,18 +264,53 @@ pub enum Destination {
 
 #[derive(Serialize, Deserialize, Clone)]
 enum NetMessageVariant {
-    Login,
+    Login {
+        version: u16,
+        payload: Vec<u8>,
+        /// Set when a client tries to get back into a session it has lost the connection to.
+        session: Option<Session>,
+    },
+    /// Sent by the host when a session has been resumed.
+    Resumed,
+    /// Sent by the host instead of `RegDone` when a login isn't accepted.
+    Rejected {
+        reason: DisconnectReason,
+    },
+    /// Sent by the host instead of `RegDone` when the client uses another `PROTOCOL_VERSION`.
+    VersionMismatch {
+        version: u16,
+    },
     Normal(NetMessageNormal),
 }
 
+/// Lets a client resume its session, see `Settings::resume_grace_period`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Session {
+    id: PeerId,
+    token: u64,
+}
+
 /// Tells how reliable a message is.
 #[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
 pub enum Reliability {
     /// Message will be delivered at most once.
     Unreliable,
+    /// Same as `Unreliable`, but messages older than the newest already delivered one are dropped.
+    /// Only sequenced relative to other `UnreliableSequenced` messages on the same channel between the same pair of peers.
+    UnreliableSequenced,
     /// Message will be resent untill is's arrival will be confirmed.
     /// Will be delivered at most once.
     Reliable,
+    /// Same as `Reliable`, but messages are also delivered in the same order they were sent in.
+    /// Only ordered relative to other `ReliableOrdered` messages on the same channel between the same pair of peers.
+    ReliableOrdered,
+}
+
+impl Reliability {
+    /// Whether messages with this reliability are resent until confirmed.
+    pub fn is_reliable(self) -> bool {
+        matches!(self, Reliability::Reliable | Reliability::ReliableOrdered)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -97,37 +318,162 @@ struct NetMessageNormal {
     src: PeerId,
     dst: Destination,
     seq_id: SeqId,
+    channel: ChannelId,
+    order_id: SeqId,
     reliability: Reliability,
     inner: NetMessageInner,
+    #[serde(skip)]
+    handle: Option<MessageHandle>,
+    #[serde(skip)]
+    deadline: Option<Instant>,
+}
+
+impl NetMessageNormal {
+    /// Whether this message has to be dropped because its deadline has passed.
+    /// `ReliableOrdered` messages never expire, as the rest of the channel would wait for them forever.
+    fn expired(&self, now: Instant) -> bool {
+        self.reliability != Reliability::ReliableOrdered
+            && self.deadline.is_some_and(|deadline| deadline < now)
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 enum NetMessageInner {
-    RegDone { addr: SocketAddr },
-    AddPeer { id: PeerId },
-    DelPeer { id: PeerId },
-    Confirm { confirmed_ids: Vec<SeqId> },
-    Payload { data: Vec<u8> },
+    RegDone {
+        addr: SocketAddr,
+        token: u64,
+    },
+    AddPeer {
+        id: PeerId,
+    },
+    DelPeer {
+        id: PeerId,
+        reason: DisconnectReason,
+    },
+    /// Confirms messages that are too old to be confirmed by the ack of a datagram.
+    Confirm {
+        confirmed_ids: Vec<SeqId>,
+    },
+    Heartbeat,
+    Payload {
+        data: Vec<u8>,
+    },
+    Fragment {
+        group: u16,
+        index: u16,
+        count: u16,
+        data: Vec<u8>,
+    },
+    /// Piece of a `Fragment` that no longer fits into a datagram, after the datagram size has shrunk.
+    /// The pieces are put back together into the whole serialized message that the fragment was sent in.
+    Refragment {
+        group: u16,
+        index: u16,
+        count: u16,
+        data: Vec<u8>,
+    },
+    TransferChunk {
+        id: TransferId,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    TransferEnd {
+        id: TransferId,
+        len: u64,
+    },
+    TransferAck {
+        id: TransferId,
+        received: u64,
+    },
+    TransferCancel {
+        id: TransferId,
+    },
+    /// Asks the receiver to not send more than this much bytes per second to the sender, replacing the limit asked for before.
+    DownloadLimit {
+        rate: u64,
+    },
+    /// The host has lost the connection to a peer, which may still resume its session.
+    PeerReconnecting {
+        id: PeerId,
+    },
+    PeerResumed {
+        id: PeerId,
+    },
+    /// Receiver is being disconnected, or the sender is leaving and won't respond anymore.
+    Goodbye {
+        reason: DisconnectReason,
+    },
+    /// Sent in a datagram of a specific size, which is known to get through once the message is confirmed.
+    MtuProbe {
+        padding: Vec<u8>,
+    },
+    /// Asks the receiver to answer with a `Pong` right away. Times are in microseconds since the Unix epoch, by the clock of the sender.
+    Ping {
+        time: u64,
+    },
+    Pong {
+        ping_time: u64,
+        time: u64,
+    },
+}
+
+impl NetMessageInner {
+    /// Bulk messages are limited by the transfer window, and paced apart from other messages.
+    fn is_bulk(&self) -> bool {
+        matches!(self, NetMessageInner::TransferChunk { .. })
+    }
+}
+
+impl NetMessageVariant {
+    fn set_local_info(&mut self, handle: MessageHandle, deadline: Option<Instant>) {
+        if let NetMessageVariant::Normal(msg) = self {
+            msg.handle = Some(handle);
+            msg.deadline = deadline;
+        }
+    }
+}
+
+/// Confirms the messages recently received from the destination of the datagram.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+struct Ack {
+    /// Newest received sequence id.
+    latest: SeqId,
+    /// Bit `n` is set if `latest - n - 1` has been received as well.
+    bits: u32,
+}
+
+impl Ack {
+    const BITS: u32 = u32::BITS;
+
+    fn confirmed_ids(self) -> impl Iterator<Item = SeqId> {
+        (0..Self::BITS)
+            .filter(move |n| self.bits & (1 << n) != 0)
+            .map(move |n| self.latest.wrapping_sub(n as SeqId + 1))
+            .chain(Some(self.latest))
+    }
+}
+
+/// Everything that is sent in a single datagram.
+#[derive(Serialize, Deserialize, Clone)]
+struct Packet {
+    ack: Option<Ack>,
+    messages: Vec<NetMessageVariant>,
 }
 
-impl TryFrom<Datagram> for NetMessageVariant {
+impl TryFrom<Datagram> for Packet {
     type Error = bincode::Error;
 
     fn try_from(datagram: Datagram) -> Result<Self, Self::Error> {
-        bincode::deserialize(&datagram.data[..datagram.size])
+        bincode::deserialize(&datagram.data)
     }
 }
 
-impl TryFrom<&NetMessageVariant> for Datagram {
+impl TryFrom<&Packet> for Datagram {
     type Error = bincode::Error;
 
-    fn try_from(value: &NetMessageVariant) -> Result<Self, Self::Error> {
-        let mut data = Cursor::new([0; DATAGRAM_MAX_LEN]);
-        bincode::serialize_into(&mut data, value)?;
-        let data = data.into_inner();
+    fn try_from(value: &Packet) -> Result<Self, Self::Error> {
         Ok(Datagram {
-            data,
-            size: data.len(),
+            data: bincode::serialize(value)?,
         })
     }
 }
@@ -135,10 +481,57 @@ impl TryFrom<&NetMessageVariant> for Datagram {
 pub(crate) struct Reactor {
     shared: Arc<Shared>,
     direct_peers: HashMap<PeerId, DirectPeer>,
+    outgoing_transfers: HashMap<TransferId, OutgoingTransfer>,
+    incoming_transfers: HashMap<TransferId, IncomingTransfer>,
+    finished_transfers: RingSet<TransferId>,
+    upload_budget: Option<ByteBudget>,
+    /// Logins waiting for `Peer::accept` or `Peer::reject`, with the time they arrived and their payload.
+    login_requests: HashMap<SocketAddr, (Instant, Vec<u8>)>,
+    /// Datagrams in a row from a direct peer that couldn't be decoded, by the address of the peer.
+    decode_failures: HashMap<SocketAddr, u32>,
+    /// Set while a client waits for the host to register it, or to resume its session.
+    login_retry: Option<LoginRetry>,
+    /// Session of a registered client.
+    session: Option<Session>,
+    /// Set by `Peer::shutdown`. The reactor stops once every reliable message is confirmed, or at this moment.
+    shutdown_deadline: Option<Instant>,
+}
+
+struct LoginRetry {
+    next: Instant,
+    delay: Duration,
+    /// When to give up on connecting. Resuming a session isn't limited by it, as the suspended host is removed once the grace period is over.
+    deadline: Option<Instant>,
 }
 
 type AddrDatagram = (SocketAddr, Datagram);
 
+/// Logins from clients of another version are rejected. Bumped on every change to the wire format.
+const PROTOCOL_VERSION: u16 = 4;
+
+/// Delay before the first resend of a login. Doubles with every attempt, up to `LOGIN_RETRY_MAX_DELAY`.
+const LOGIN_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+const LOGIN_RETRY_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Longest the reactor waits for the threads reading streams of unfinished transfers to stop, once it stops itself.
+const READER_JOIN_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Amount of times a goodbye is sent to each peer on disconnect.
+const GOODBYE_COPIES: usize = 3;
+
+/// Messages from the queues are held back once they would get this far ahead of the oldest unconfirmed message in sequence ids.
+/// Once the other side moves its `SeqWindow` past a message, it can't tell a resend of it from a duplicate anymore.
+/// The rest of the window is left to messages sent outside of the queues, such as heartbeats, which can't be held back.
+const MAX_SEQ_SPAN: usize = SeqWindow::SIZE / 2;
+
+/// A single datagram that can't be decoded may just be corrupted, but a direct peer that keeps sending them is dropped after this many in a row.
+const MAX_DECODE_FAILURES: u32 = 8;
+
+/// While logging in or resuming, the host address has to send this many undecodable datagrams in a row to be taken for something other than a host of this protocol version.
+/// Fewer are needed than from a direct peer, as at most one answer comes for each login attempt.
+const MAX_LOGIN_DECODE_FAILURES: u32 = 3;
+
 impl Reactor {
     fn add_peer(&self, id: PeerId) -> Result<(), NetError> {
         self.shared.remote_peers.insert(id, RemotePeer::default());
@@ -152,15 +545,15 @@ impl Reactor {
     fn direct_broadcast(
         &mut self,
         my_id: PeerId,
+        channel: ChannelId,
         msg: NetMessageInner,
         reliability: Reliability,
     ) -> Result<(), NetError> {
         for (&peer_id, peer) in self.direct_peers.iter_mut() {
-            let new_seq_id = peer.seq_counter.fetch_add(1, SeqCst);
-            let new_msg = Self::wrap_packet_seq_id(
+            let new_msg = Self::wrap_packet_from(
                 my_id,
-                new_seq_id,
                 Destination::One(peer_id),
+                channel,
                 msg.clone(),
                 reliability,
             )?;
@@ -178,10 +571,251 @@ impl Reactor {
     }
 
     fn direct_send_peer(peer: &mut DirectPeer, msg: NetMessageVariant) -> Result<(), NetError> {
+        let mut msg = match msg {
+            NetMessageVariant::Normal(msg) => msg,
+            msg => {
+                peer.outbound_pending.push_back(msg);
+                return Ok(());
+            }
+        };
+        if msg.reliability == Reliability::UnreliableSequenced {
+            // All fragments of a message share the same sequence id.
+            let channel = peer.channel(msg.channel);
+            msg.order_id = channel.sequence_counter;
+            channel.sequence_counter = channel.sequence_counter.wrapping_add(1);
+        }
+        for mut part in peer.fragment(msg) {
+            if let Some(handle) = part.handle.filter(|_| part.reliability.is_reliable()) {
+                *peer.unconfirmed.entry(handle).or_default() += 1;
+            }
+            if part.reliability == Reliability::ReliableOrdered {
+                let channel = peer.channel(part.channel);
+                part.order_id = channel.order_counter;
+                channel.order_counter = channel.order_counter.wrapping_add(1);
+            }
+            if part.inner.is_bulk() {
+                peer.bulk_pending.push_back(part);
+            } else {
+                peer.outbound_pending
+                    .push_back(NetMessageVariant::Normal(part));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gives a new client an id and introduces it to everyone.
+    fn register_client(&mut self, addr: SocketAddr, payload: Vec<u8>) {
+        let Some(new_id) = self.gen_peer_id() else {
+            warn!("Out of ids");
+            return;
+        };
+        let id = PeerId(0);
+        self.add_peer(new_id).ok();
+        let mut peer = DirectPeer::new(addr, self.shared.settings.max_upload_rate);
+        peer.session_token = Some(random_u64());
+        peer.login_payload = payload;
+        self.direct_peers.insert(new_id, peer);
+        self.send_reg_done(new_id).ok();
+        if self.shared.settings.max_total_download_rate.is_some() {
+            // Everyone's share got smaller.
+            self.announce_download_limits();
+        } else {
+            self.announce_download_limit(new_id);
+        }
+        self.direct_broadcast(
+            id,
+            ChannelId::default(),
+            NetMessageInner::AddPeer { id: new_id },
+            Reliability::Reliable,
+        )
+        .ok();
+        let shared = self.shared.clone();
+        for re in shared.remote_peers.iter() {
+            let id = *re.key();
+            // The host itself is announced by `RegDone`.
+            if id != new_id && id != PeerId(0) {
+                self.wrap_packet(
+                    new_id,
+                    Destination::One(new_id),
+                    ChannelId::default(),
+                    NetMessageInner::AddPeer { id },
+                    Reliability::Reliable,
+                )
+                .and_then(|msg| self.direct_send(new_id, msg))
+                .ok();
+            }
+        }
+    }
+
+    /// Resends the login of a client until the host answers, giving up after `Settings::connect_timeout`.
+    fn retry_login(&mut self) {
+        let Some(retry) = &mut self.login_retry else {
+            return;
+        };
+        let now = Instant::now();
+        if retry.deadline.is_some_and(|deadline| now >= deadline) {
+            self.connect_failed(ConnectError::Unreachable);
+        } else if now >= retry.next {
+            retry.next = now + retry.delay;
+            retry.delay = (retry.delay * 2).min(LOGIN_RETRY_MAX_DELAY);
+            self.send_login();
+        }
+    }
+
+    fn send_login(&mut self) {
+        let msg = NetMessageVariant::Login {
+            version: PROTOCOL_VERSION,
+            payload: self.shared.login_payload.clone(),
+            session: self.session,
+        };
+        let host_addr = self.shared.host_addr.expect("Only clients log in");
+        // Sent past the queues, which are held back while the host is suspended.
+        PacketWriter::new(&self.shared.socket, host_addr, None, DATAGRAM_DEFAULT_LEN)
+            .send_alone(msg);
+    }
+
+    /// Lets a client continue its session after losing the connection, keeping its id and the messages that are still pending.
+    fn resume_session(&mut self, session: Session, addr: SocketAddr) -> Result<(), NetError> {
+        let Some(peer) = self
+            .direct_peers
+            .get_mut(&session.id)
+            .filter(|peer| peer.session_token == Some(session.token))
+        else {
+            info!("[Host] Session of peer {} can't be resumed", session.id);
+            self.reject_login(addr, DisconnectReason::SessionExpired);
+            return Ok(());
+        };
+        if peer.suspended.is_none() {
+            if peer.addr == addr {
+                // The client has timed out before the host did, or the first `Resumed` got lost.
+                PacketWriter::new(&self.shared.socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                    .send_alone(NetMessageVariant::Resumed);
+            } else {
+                // Once the host times out as well, the session can be resumed from the new address.
+                info!(
+                    "[Host] Peer {} is still connected, resume from {} ignored",
+                    session.id, addr
+                );
+            }
+            return Ok(());
+        }
+        peer.addr = addr;
+        peer.last_seen = Instant::now();
+        peer.suspended = None;
+        PacketWriter::new(&self.shared.socket, addr, None, DATAGRAM_DEFAULT_LEN)
+            .send_alone(NetMessageVariant::Resumed);
+        info!("[Host] Peer {} resumed", session.id);
+        self.shared
+            .inbound_channel
+            .0
+            .send(NetworkEvent::PeerResumed(session.id))?;
+        self.direct_broadcast(
+            PeerId(0),
+            ChannelId::default(),
+            NetMessageInner::PeerResumed { id: session.id },
+            Reliability::Reliable,
+        )
+    }
+
+    /// Holds on to a direct peer that has timed out, in case it comes back within `Settings::resume_grace_period`.
+    /// A client that lost the host starts trying to resume its session.
+    fn suspend_direct_peer(&mut self, id: PeerId) -> Result<(), NetError> {
+        let Some(peer) = self.direct_peers.get_mut(&id) else {
+            return Ok(());
+        };
+        peer.suspended = Some(Instant::now());
+        info!("Peer {} is reconnecting", id);
+        self.shared
+            .inbound_channel
+            .0
+            .send(NetworkEvent::PeerReconnecting(id))?;
+        if self.is_host() {
+            self.direct_broadcast(
+                PeerId(0),
+                ChannelId::default(),
+                NetMessageInner::PeerReconnecting { id },
+                Reliability::Reliable,
+            )?;
+        } else {
+            self.shared.set_state(PeerState::Reconnecting);
+            self.login_retry = Some(LoginRetry {
+                next: Instant::now(),
+                delay: LOGIN_RETRY_DELAY,
+                deadline: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stops a client which couldn't get registered by the host.
+    fn connect_failed(&mut self, err: ConnectError) {
+        info!("Could not connect: {:?}", err);
+        self.login_retry = None;
+        self.direct_peers.clear();
+        self.shared.set_state(PeerState::ConnectFailed(err));
+        self.shared.keep_alive.store(false, SeqCst);
+    }
+
+    fn handle_pong(&mut self, id: PeerId, ping_time: u64, time: u64) -> Result<(), NetError> {
+        let Some(peer) = self.direct_peers.get_mut(&id) else {
+            return Ok(());
+        };
+        let now = unix_micros();
+        let ping = Duration::from_micros(now.saturating_sub(ping_time));
+        // The pong is assumed to have been sent halfway through the round trip.
+        let clock_offset = time as i64 - (ping_time / 2 + now / 2) as i64;
+        // The same round-trip time is reported everywhere, so pings count towards the one used for resends.
+        peer.rtt.add_sample(ping);
+        let rtt = peer.rtt.srtt().unwrap_or(ping);
+        let stats = peer.latency.on_pong(ping, rtt, clock_offset);
+        if let Some(mut remote) = self.shared.remote_peers.get_mut(&id) {
+            remote.rtt = Some(rtt);
+            remote.stats = Some(stats);
+        }
+        if self.shared.settings.latency_events {
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::LatencyUpdate { id, stats })?;
+        }
+        Ok(())
+    }
+
+    /// Tells a client its id and session token.
+    /// Also sent again when a registered client repeats its login, as the first one may be late or lost.
+    fn send_reg_done(&mut self, id: PeerId) -> Result<(), NetError> {
+        let peer = self
+            .direct_peers
+            .get_mut(&id)
+            .ok_or(NetError::UnknownPeer)?;
+        let token = peer.session_token.ok_or(NetError::UnknownPeer)?;
+        let msg = Self::wrap_packet_from(
+            PeerId(0),
+            Destination::One(id),
+            ChannelId::default(),
+            NetMessageInner::RegDone {
+                addr: peer.addr,
+                token,
+            },
+            Reliability::Reliable,
+        )?;
         peer.outbound_pending.push_back(msg);
         Ok(())
     }
 
+    fn peer_by_addr(&self, addr: SocketAddr) -> Option<PeerId> {
+        self.direct_peers
+            .iter()
+            .find(|(_, peer)| peer.addr == addr)
+            .map(|(&id, _)| id)
+    }
+
+    /// Tells a client that its login isn't accepted. There is no connection to resend it over, so it's sent just once.
+    fn reject_login(&self, addr: SocketAddr, reason: DisconnectReason) {
+        PacketWriter::new(&self.shared.socket, addr, None, DATAGRAM_DEFAULT_LEN)
+            .send_alone(NetMessageVariant::Rejected { reason });
+    }
+
     fn gen_peer_id(&mut self) -> Option<PeerId> {
         (1..=u16::MAX)
             .map(PeerId)
@@ -189,67 +823,159 @@ impl Reactor {
     }
 
     fn handle_inbound(&mut self, (incoming_addr, msg_raw): AddrDatagram) {
-        let msg = match NetMessageVariant::try_from(msg_raw) {
-            Ok(msg) => msg,
+        let Packet { mut ack, messages } = match Packet::try_from(msg_raw) {
+            Ok(packet) => {
+                if !self.decode_failures.is_empty() {
+                    self.decode_failures.remove(&incoming_addr);
+                }
+                packet
+            }
             Err(err) => {
                 warn!("Error when converting to NetMessage: {}", err);
+                let sender = self.peer_by_addr(incoming_addr);
+                let logging_in =
+                    self.login_retry.is_some() && Some(incoming_addr) == self.shared.host_addr;
+                if logging_in || sender.is_some() {
+                    let failures = self.decode_failures.entry(incoming_addr).or_default();
+                    *failures += 1;
+                    let max = if logging_in {
+                        MAX_LOGIN_DECODE_FAILURES
+                    } else {
+                        MAX_DECODE_FAILURES
+                    };
+                    if *failures >= max {
+                        self.decode_failures.remove(&incoming_addr);
+                        if logging_in {
+                            self.connect_failed(ConnectError::ProtocolMismatch);
+                        } else if let Some(id) = sender {
+                            warn!("Dropping peer {} for sending undecodable datagrams", id);
+                            self.drop_peer(id, DisconnectReason::ProtocolError).ok();
+                        }
+                    }
+                }
                 return;
             }
         };
+        for msg in messages {
+            self.handle_inbound_message(msg, &mut ack, incoming_addr);
+        }
+    }
+
+    /// Handles one of the messages of a datagram. The ack of the datagram is handled along with the first normal message.
+    fn handle_inbound_message(
+        &mut self,
+        msg: NetMessageVariant,
+        ack: &mut Option<Ack>,
+        incoming_addr: SocketAddr,
+    ) {
         match self.shared.my_id.load() {
             Some(id) => {
                 match msg {
-                    NetMessageVariant::Login => {
+                    NetMessageVariant::Login {
+                        version,
+                        payload,
+                        session,
+                    } => {
                         if self.is_host() {
-                            //TODO check this addr is not already registered
-                            match self.gen_peer_id() {
-                                Some(new_id) => {
-                                    self.add_peer(new_id).ok();
-                                    let mut peer = DirectPeer::new(
-                                        incoming_addr,
-                                        self.shared.max_packets_per_second,
-                                    );
-                                    peer.outbound_pending.push_back(NetMessageVariant::Normal(
-                                        NetMessageNormal {
-                                            src: id,
-                                            dst: Destination::One(new_id),
-                                            seq_id: u16::MAX,
-                                            inner: NetMessageInner::RegDone {
-                                                addr: incoming_addr,
-                                            },
-                                            reliability: Reliability::Reliable,
-                                        },
-                                    ));
-                                    self.direct_peers.insert(new_id, peer);
-                                    self.direct_broadcast(
-                                        id,
-                                        NetMessageInner::AddPeer { id: new_id },
-                                        Reliability::Reliable,
-                                    )
+                            if version != PROTOCOL_VERSION {
+                                info!(
+                                    "[Host] Login from {} with protocol version {} rejected",
+                                    incoming_addr, version
+                                );
+                                PacketWriter::new(
+                                    &self.shared.socket,
+                                    incoming_addr,
+                                    None,
+                                    DATAGRAM_DEFAULT_LEN,
+                                )
+                                .send_alone(
+                                    NetMessageVariant::VersionMismatch {
+                                        version: PROTOCOL_VERSION,
+                                    },
+                                );
+                                return;
+                            }
+                            if self.shared.bans.contains(&incoming_addr.ip()) {
+                                info!(
+                                    "[Host] Login from banned address {} rejected",
+                                    incoming_addr
+                                );
+                                let reason = DisconnectReason::Banned("Banned".to_owned());
+                                self.reject_login(incoming_addr, reason);
+                                return;
+                            }
+                            if self.shared.identity_bans.contains(&payload) {
+                                info!(
+                                    "[Host] Login from {} with a banned identity rejected",
+                                    incoming_addr
+                                );
+                                let reason = DisconnectReason::Banned("Banned".to_owned());
+                                self.reject_login(incoming_addr, reason);
+                                return;
+                            }
+                            if let Some(session) = session {
+                                self.resume_session(session, incoming_addr).ok();
+                                return;
+                            }
+                            if let Some(id) = self.peer_by_addr(incoming_addr) {
+                                // The first `RegDone` may have been lost or is still on the way.
+                                trace!("[Host] Repeated login from peer {}", id);
+                                self.send_reg_done(id).ok();
+                                return;
+                            }
+                            if !self.shared.settings.manual_accept {
+                                self.register_client(incoming_addr, payload);
+                            } else if let Entry::Vacant(entry) =
+                                self.login_requests.entry(incoming_addr)
+                            {
+                                // Resent logins don't extend the time to answer.
+                                entry.insert((Instant::now(), payload.clone()));
+                                self.shared
+                                    .inbound_channel
+                                    .0
+                                    .send(NetworkEvent::ConnectionRequest {
+                                        addr: incoming_addr,
+                                        payload,
+                                    })
                                     .ok();
-                                    let shared = self.shared.clone();
-                                    for re in shared.remote_peers.iter() {
-                                        let id = *re.key();
-                                        if id != new_id {
-                                            self.wrap_packet(
-                                                id,
-                                                Destination::One(new_id),
-                                                NetMessageInner::AddPeer { id },
-                                                Reliability::Reliable,
-                                            )
-                                            .and_then(|msg| self.direct_send(new_id, msg))
-                                            .ok();
-                                        }
-                                    }
-                                }
-                                None => warn!("Out of ids"),
                             }
                         } else {
                             warn!("Not a host, registration attempt ignored");
                         }
                     }
+                    NetMessageVariant::Resumed => {
+                        if self.login_retry.is_some()
+                            && Some(incoming_addr) == self.shared.host_addr
+                        {
+                            info!("Session resumed");
+                            self.login_retry = None;
+                            if let Some(host) = self.direct_peers.get_mut(&PeerId(0)) {
+                                host.suspended = None;
+                                host.last_seen = Instant::now();
+                            }
+                            self.shared.set_state(PeerState::Connected);
+                            self.shared
+                                .inbound_channel
+                                .0
+                                .send(NetworkEvent::PeerResumed(PeerId(0)))
+                                .ok();
+                        }
+                    }
+                    NetMessageVariant::Rejected { reason } => {
+                        if self.login_retry.is_some()
+                            && Some(incoming_addr) == self.shared.host_addr
+                        {
+                            info!("Session can't be resumed: {:?}", reason);
+                            self.remove_direct_peer(PeerId(0), reason).ok();
+                        } else {
+                            warn!("Already registered, rejection ignored");
+                        }
+                    }
+                    NetMessageVariant::VersionMismatch { .. } => {
+                        warn!("Already registered, rejection ignored");
+                    }
                     NetMessageVariant::Normal(msg) => {
-                        match self.handle_inbound_normal(msg, incoming_addr, id) {
+                        match self.handle_inbound_normal(msg, ack.take(), incoming_addr, id) {
                             Ok(_) => {}
                             Err(NetError::Dropped) => {}
                             Err(err) => {
@@ -261,9 +987,10 @@ impl Reactor {
             }
             None => match msg {
                 NetMessageVariant::Normal(NetMessageNormal {
-                    inner: NetMessageInner::RegDone { addr: _ },
+                    inner: NetMessageInner::RegDone { addr: _, token },
                     dst,
                     src,
+                    seq_id,
                     ..
                 }) => {
                     let expected_host_addr = self
@@ -273,8 +1000,15 @@ impl Reactor {
                     if incoming_addr == expected_host_addr && src == PeerId(0) {
                         if let Destination::One(id) = dst {
                             self.shared.my_id.store(Some(id));
+                            self.session = Some(Session { id, token });
+                            if let Some(host) = self.direct_peers.get_mut(&PeerId(0)) {
+                                host.mark_received(seq_id);
+                                host.last_seen = Instant::now();
+                            }
+                            self.login_retry = None;
                             self.add_peer(PeerId(0)).ok();
-                            self.shared.peer_state.store(PeerState::Connected);
+                            self.announce_download_limit(PeerId(0));
+                            self.shared.set_state(PeerState::Connected);
                         } else {
                             warn!("Malformed registration message");
                         }
@@ -282,6 +1016,24 @@ impl Reactor {
                         warn!("Registration message recieved not from the right address ({}, {} expected)", incoming_addr, expected_host_addr);
                     }
                 }
+                NetMessageVariant::Rejected { reason } => {
+                    if Some(incoming_addr) == self.shared.host_addr {
+                        self.connect_failed(ConnectError::Rejected(reason));
+                    } else {
+                        warn!("Rejection recieved not from the host ({})", incoming_addr);
+                    }
+                }
+                NetMessageVariant::VersionMismatch { version } => {
+                    if Some(incoming_addr) == self.shared.host_addr {
+                        info!(
+                            "Host uses protocol version {}, {} expected",
+                            version, PROTOCOL_VERSION
+                        );
+                        self.connect_failed(ConnectError::ProtocolMismatch);
+                    } else {
+                        warn!("Rejection recieved not from the host ({})", incoming_addr);
+                    }
+                }
                 _ => warn!("Message ignored as registration is not done yet"),
             },
         }
@@ -290,26 +1042,164 @@ impl Reactor {
     fn handle_inbound_normal(
         &mut self,
         msg: NetMessageNormal,
-        _incoming_addr: SocketAddr,
+        ack: Option<Ack>,
+        incoming_addr: SocketAddr,
         my_id: PeerId,
     ) -> Result<(), NetError> {
-        let peer = self.direct_peers.get_mut(&msg.src);
-        if peer
-            .as_ref()
-            .map_or(true, |peer| peer.recent_seq.contains(&msg.seq_id))
-        {
+        let Some(peer) = self.direct_peers.get_mut(&msg.src) else {
+            return Err(NetError::Dropped);
+        };
+        // Anyone can put any id into a message, only the address tells who sent it.
+        if peer.addr != incoming_addr {
+            warn!(
+                "Message from {} claims to be from peer {}, ignored",
+                incoming_addr, msg.src
+            );
             return Err(NetError::Dropped);
         }
-        {
-            let peer = peer.expect("Expected to exist");
-            peer.recent_seq.add(msg.seq_id); //TODO backpressure
-            peer.pending_confirms.push_back(msg.seq_id);
-            peer.last_seen = Instant::now()
+        if let Some(ack) = ack {
+            peer.last_seen = Instant::now();
+            self.handle_confirms(msg.src, ack.confirmed_ids())?;
+        }
+
+        let Some(peer) = self.direct_peers.get_mut(&msg.src) else {
+            return Err(NetError::Dropped);
+        };
+        if peer.recent_seq.contains(msg.seq_id) {
+            // Being resent means that the confirm didn't get through, possibly because the ack can't reach back to it anymore.
+            // Ids too old to be tracked can't be told apart from ones that never arrived, so those aren't confirmed.
+            if msg.reliability.is_reliable() && peer.recent_seq.recorded(msg.seq_id) {
+                if !peer.ack_covers(msg.seq_id) {
+                    peer.late_confirms.push(msg.seq_id);
+                }
+                peer.confirm_due
+                    .get_or_insert_with(|| Instant::now() + self.shared.settings.confirm_delay);
+            }
+            return Err(NetError::Dropped);
+        }
+        let ordered = msg.reliability == Reliability::ReliableOrdered;
+        let mut held = 0;
+        if ordered {
+            let ordered_inbound = &peer.channel(msg.channel).ordered_inbound;
+            if !ordered_inbound.accepts(msg.order_id) {
+                // Not confirmed, so it will be resent once there is space for it.
+                return Err(NetError::Dropped);
+            }
+            if ordered_inbound.holds_back(msg.order_id) {
+                held = bincode::serialized_size(&msg).unwrap() as usize;
+                if peer.fragment_bytes + peer.held_bytes + held
+                    > self.shared.settings.max_fragment_buffer
+                {
+                    warn!(
+                        "Reorder buffer for {} is full, message left to be resent",
+                        msg.src
+                    );
+                    return Err(NetError::Dropped);
+                }
+            }
+        }
+        peer.mark_received(msg.seq_id); //TODO backpressure
+        if msg.reliability.is_reliable() || matches!(msg.inner, NetMessageInner::MtuProbe { .. }) {
+            peer.confirm_due
+                .get_or_insert_with(|| Instant::now() + self.shared.settings.confirm_delay);
+            peer.received_since_ack.push(msg.seq_id);
+        }
+        self.deliver_inbound(msg, held, my_id)
+    }
+
+    /// Hands over a message that isn't a duplicate, once the ones ordered before it are there.
+    /// `held` is its size if the reorder buffer holds it back.
+    fn deliver_inbound(
+        &mut self,
+        msg: NetMessageNormal,
+        held: usize,
+        my_id: PeerId,
+    ) -> Result<(), NetError> {
+        let Some(peer) = self.direct_peers.get_mut(&msg.src) else {
+            return Err(NetError::Dropped);
+        };
+        let ordered = msg.reliability == Reliability::ReliableOrdered;
+        let channel = peer.channel(msg.channel);
+
+        if msg.reliability == Reliability::UnreliableSequenced {
+            if channel
+                .last_sequenced
+                .is_some_and(|last| seq_newer(last, msg.order_id))
+            {
+                return Err(NetError::Dropped);
+            }
+            channel.last_sequenced = Some(msg.order_id);
         }
 
+        if ordered {
+            let ready = channel.ordered_inbound.push(msg.order_id, msg);
+            peer.held_bytes += held;
+            // Everything after the first one has been held back.
+            for msg in ready.iter().skip(1) {
+                peer.held_bytes -= bincode::serialized_size(msg).unwrap() as usize;
+            }
+            for msg in ready {
+                self.process_inbound_normal(msg, my_id)?;
+            }
+            Ok(())
+        } else {
+            self.process_inbound_normal(msg, my_id)
+        }
+    }
+
+    /// Handles confirms of messages sent to a direct peer, which come either from an ack or from a `Confirm` message.
+    fn handle_confirms(
+        &mut self,
+        id: PeerId,
+        seq_ids: impl IntoIterator<Item = SeqId>,
+    ) -> Result<(), NetError> {
+        let Some(peer) = self.direct_peers.get_mut(&id) else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        let delivered: Vec<_> = seq_ids
+            .into_iter()
+            .filter_map(|seq_id| {
+                peer.mtu.on_ack(seq_id, now);
+                peer.confirm(seq_id)
+            })
+            .collect();
+        if let Some(mut remote) = self.shared.remote_peers.get_mut(&id) {
+            remote.rtt = peer.rtt.srtt();
+            remote.send_rate = Some(peer.congestion.rate());
+            remote.mtu = Some(peer.mtu.current());
+        }
+        for handle in delivered {
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::Delivered(handle))?;
+        }
+        Ok(())
+    }
+
+    fn process_inbound_normal(
+        &mut self,
+        msg: NetMessageNormal,
+        my_id: PeerId,
+    ) -> Result<(), NetError> {
+        let msg = match msg.inner {
+            NetMessageInner::Fragment { .. } => match self.reassemble(msg) {
+                Some(msg) => msg,
+                None => return Ok(()),
+            },
+            NetMessageInner::Refragment { .. } => {
+                return match self.reassemble(msg) {
+                    Some(msg) => self.handle_refragmented(msg, my_id),
+                    None => Ok(()),
+                };
+            }
+            _ => msg,
+        };
+
         if Destination::One(my_id) == msg.dst {
             match msg.inner {
-                NetMessageInner::RegDone { addr: _ } => {
+                NetMessageInner::RegDone { .. } => {
                     warn!("Already registered, request ignored");
                 }
                 NetMessageInner::AddPeer { id } => {
@@ -318,35 +1208,122 @@ impl Reactor {
                         info!("Peer {} added", id);
                     }
                 }
-                NetMessageInner::DelPeer { id } => {
+                NetMessageInner::DelPeer { id, reason } => {
                     if !self.is_host() {
-                        self.del_peer(id).ok();
-                        info!("Peer {} removed", id);
+                        info!("Peer {} removed: {:?}", id, reason);
+                        self.del_peer(id, reason).ok();
+                    }
+                }
+                NetMessageInner::PeerReconnecting { id } => {
+                    if !self.is_host() && id != my_id {
+                        self.shared
+                            .inbound_channel
+                            .0
+                            .send(NetworkEvent::PeerReconnecting(id))?;
+                    }
+                }
+                NetMessageInner::PeerResumed { id } => {
+                    if !self.is_host() && id != my_id {
+                        self.shared
+                            .inbound_channel
+                            .0
+                            .send(NetworkEvent::PeerResumed(id))?;
                     }
                 }
                 NetMessageInner::Confirm { confirmed_ids } => {
-                    if let Some(peer) = self.direct_peers.get_mut(&msg.src) {
-                        for id in confirmed_ids {
-                            peer.confirmed.add(id);
+                    self.handle_confirms(msg.src, confirmed_ids)?
+                }
+                NetMessageInner::Heartbeat => {}
+                NetMessageInner::Fragment { .. } | NetMessageInner::Refragment { .. } => {
+                    unreachable!("Fragments are reassembled before being handled")
+                }
+                NetMessageInner::TransferChunk { id, offset, data } => {
+                    if self.track_incoming_transfer(id)? {
+                        if let Some(transfer) = self.incoming_transfers.get_mut(&id) {
+                            if !transfer.add(offset, data) {
+                                warn!("Transfer {:?} has a chunk out of range, cancelled", id);
+                                return self.cancel_transfer(id);
+                            }
+                        }
+                        self.update_incoming_transfer(id)?;
+                    }
+                }
+                NetMessageInner::TransferEnd { id, len } => {
+                    if self.track_incoming_transfer(id)? {
+                        if let Some(transfer) = self.incoming_transfers.get_mut(&id) {
+                            transfer.set_len(len);
                         }
+                        self.update_incoming_transfer(id)?;
+                    }
+                }
+                NetMessageInner::TransferAck { id, received } => {
+                    if let Some(transfer) = self.outgoing_transfers.get_mut(&id) {
+                        transfer.ack(received);
+                    }
+                }
+                NetMessageInner::TransferCancel { id } => {
+                    if self.outgoing_transfers.remove(&id).is_some()
+                        || self.incoming_transfers.remove(&id).is_some()
+                    {
+                        self.finished_transfers.add(id);
+                        self.shared
+                            .inbound_channel
+                            .0
+                            .send(NetworkEvent::TransferFailed(id))?;
+                    }
+                }
+                NetMessageInner::MtuProbe { .. } => {}
+                NetMessageInner::Ping { time } => {
+                    if self.direct_peers.contains_key(&msg.src) {
+                        self.wrap_packet(
+                            msg.src,
+                            Destination::One(msg.src),
+                            ChannelId::default(),
+                            NetMessageInner::Pong {
+                                ping_time: time,
+                                time: unix_micros(),
+                            },
+                            Reliability::Unreliable,
+                        )
+                        .and_then(|pong| self.direct_send(msg.src, pong))?;
+                    }
+                }
+                NetMessageInner::Pong { ping_time, time } => {
+                    self.handle_pong(msg.src, ping_time, time)?;
+                }
+                NetMessageInner::Goodbye { reason } => {
+                    info!("Peer {} said goodbye: {:?}", msg.src, reason);
+                    self.remove_direct_peer(msg.src, reason)?;
+                }
+                NetMessageInner::DownloadLimit { rate } => {
+                    if let Some(peer) = self.direct_peers.get_mut(&msg.src) {
+                        peer.limit_upload(rate, self.shared.settings.max_upload_rate);
                     }
                 }
                 NetMessageInner::Payload { data } => {
                     self.shared
                         .inbound_channel
                         .0
-                        .send(NetworkEvent::Message(Message { data }))?;
+                        .send(NetworkEvent::Message(Message {
+                            channel: msg.channel,
+                            data,
+                        }))?;
                 }
             }
         } else if self.is_host() {
             match msg.dst {
                 Destination::One(dst) => {
-                    let new_msg =
-                        self.wrap_packet(dst, Destination::One(dst), msg.inner, msg.reliability)?;
+                    let new_msg = self.wrap_packet(
+                        dst,
+                        Destination::One(dst),
+                        msg.channel,
+                        msg.inner,
+                        msg.reliability,
+                    )?;
                     self.direct_send(dst, new_msg)?;
                 }
                 Destination::Broadcast => {
-                    self.direct_broadcast(my_id, msg.inner, msg.reliability)?;
+                    self.direct_broadcast(my_id, msg.channel, msg.inner, msg.reliability)?;
                 }
             }
         }
@@ -354,169 +1331,910 @@ impl Reactor {
         Ok(())
     }
 
-    fn del_peer(&mut self, id: PeerId) -> Result<(), NetError> {
-        self.shared.remote_peers.remove(&id);
-        self.shared
-            .inbound_channel
-            .0
-            .send(NetworkEvent::PeerDisconnected(id))?;
-        Ok(())
+    /// Handles a fragment that was sent in `Refragment` pieces, as if it had arrived in a datagram of its own.
+    fn handle_refragmented(
+        &mut self,
+        msg: NetMessageNormal,
+        my_id: PeerId,
+    ) -> Result<(), NetError> {
+        let NetMessageInner::Payload { data } = msg.inner else {
+            unreachable!("Reassembled messages carry a payload")
+        };
+        let fragment = match bincode::deserialize::<NetMessageNormal>(&data) {
+            Ok(
+                fragment @ NetMessageNormal {
+                    inner: NetMessageInner::Fragment { .. },
+                    ..
+                },
+            ) if fragment.src == msg.src => fragment,
+            _ => {
+                warn!("Malformed refragmented message from {} ignored", msg.src);
+                return Ok(());
+            }
+        };
+        let Some(peer) = self.direct_peers.get_mut(&fragment.src) else {
+            return Ok(());
+        };
+        let mut held = 0;
+        if fragment.reliability == Reliability::ReliableOrdered {
+            let ordered_inbound = &peer.channel(fragment.channel).ordered_inbound;
+            if !ordered_inbound.accepts(fragment.order_id) {
+                return Ok(());
+            }
+            if ordered_inbound.holds_back(fragment.order_id) {
+                // The pieces have been confirmed already, so it is held back even if the buffer is full.
+                held = bincode::serialized_size(&fragment).unwrap() as usize;
+            }
+        }
+        self.deliver_inbound(fragment, held, my_id)
     }
 
-    fn handle_outbound(&mut self, msg: OutboundMessage) -> Result<(), NetError> {
-        let dst = msg.dst;
-        if self.is_host() {
-            match dst {
-                Destination::One(id) => {
-                    let net_msg = self.wrap_packet(
-                        id,
-                        dst,
-                        NetMessageInner::Payload { data: msg.data },
+    /// Stores a fragment, returning the whole message once all of its fragments have arrived.
+    fn reassemble(&mut self, msg: NetMessageNormal) -> Option<NetMessageNormal> {
+        let refragment = matches!(msg.inner, NetMessageInner::Refragment { .. });
+        let (NetMessageInner::Fragment {
+            group,
+            index,
+            count,
+            data,
+        }
+        | NetMessageInner::Refragment {
+            group,
+            index,
+            count,
+            data,
+        }) = msg.inner
+        else {
+            return Some(msg);
+        };
+        // Pieces of a fragment add up to no more than a datagram.
+        let max_len = if refragment {
+            DATAGRAM_MAX_LEN
+        } else {
+            self.shared.settings.max_message_len
+        };
+        if index >= count
+            || count as usize > max_len.div_ceil(MAX_MESSAGE_LEN)
+            || data.len() > DATAGRAM_MAX_LEN
+        {
+            warn!("Malformed fragment from {} ignored", msg.src);
+            return None;
+        }
+        let peer = self.direct_peers.get_mut(&msg.src)?;
+        let size = if peer.fragments.contains_key(&group) {
+            data.len()
+        } else {
+            data.len() + count as usize * size_of::<Option<Vec<u8>>>()
+        };
+        if peer.fragment_bytes + peer.held_bytes + size > self.shared.settings.max_fragment_buffer {
+            warn!("Fragment buffer for {} is full, fragment ignored", msg.src);
+            return None;
+        }
+        let partial = peer
+            .fragments
+            .entry(group)
+            .or_insert_with(|| PartialMessage {
+                started: Instant::now(),
+                parts: vec![None; count as usize],
+                remaining: count as usize,
+                size: 0,
+            });
+        if partial.parts.len() != count as usize {
+            warn!("Fragment count mismatch from {}, fragment ignored", msg.src);
+            return None;
+        }
+        let part = &mut partial.parts[index as usize];
+        if part.is_none() {
+            *part = Some(data);
+            partial.remaining -= 1;
+            partial.size += size;
+            peer.fragment_bytes += size;
+        }
+        if partial.remaining > 0 {
+            return None;
+        }
+        let partial = peer.fragments.remove(&group)?;
+        peer.fragment_bytes -= partial.size;
+        let data: Vec<u8> = partial.parts.into_iter().flatten().flatten().collect();
+        if data.len() > max_len {
+            warn!("Reassembled message from {} is too long, ignored", msg.src);
+            return None;
+        }
+        Some(NetMessageNormal {
+            inner: NetMessageInner::Payload { data },
+            ..msg
+        })
+    }
+
+    /// Starts tracking an incoming transfer if it's new, returning false if it has already finished.
+    fn track_incoming_transfer(&mut self, id: TransferId) -> Result<bool, NetError> {
+        if self.finished_transfers.contains(&id) {
+            return Ok(false);
+        }
+        if let Entry::Vacant(entry) = self.incoming_transfers.entry(id) {
+            entry.insert(IncomingTransfer::new());
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::TransferStarted(id))?;
+        }
+        Ok(true)
+    }
+
+    fn update_incoming_transfer(&mut self, id: TransferId) -> Result<(), NetError> {
+        let Some(transfer) = self.incoming_transfers.get_mut(&id) else {
+            return Ok(());
+        };
+        if transfer.size() > self.shared.settings.max_transfer_len {
+            warn!("Transfer {:?} is too long, cancelled", id);
+            return self.cancel_transfer(id);
+        }
+        let ack = transfer.take_ack(self.shared.settings.transfer_window);
+        let complete = transfer.is_complete();
+        if let Some(received) = ack {
+            self.send_to(
+                id.sender,
+                NetMessageInner::TransferAck { id, received },
+                Reliability::Reliable,
+            )?;
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::TransferProgress { id, received })?;
+        }
+        if complete {
+            let transfer = self
+                .incoming_transfers
+                .remove(&id)
+                .expect("Checked that transfer exists");
+            self.finished_transfers.add(id);
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::TransferCompleted {
+                    id,
+                    data: transfer.into_data(),
+                })?;
+        }
+        Ok(())
+    }
+
+    fn cancel_transfer(&mut self, id: TransferId) -> Result<(), NetError> {
+        let other = if let Some(transfer) = self.outgoing_transfers.remove(&id) {
+            transfer.dst
+        } else if self.incoming_transfers.remove(&id).is_some() {
+            self.finished_transfers.add(id);
+            id.sender
+        } else {
+            return Ok(());
+        };
+        self.shared
+            .inbound_channel
+            .0
+            .send(NetworkEvent::TransferFailed(id))?;
+        self.send_to(
+            other,
+            NetMessageInner::TransferCancel { id },
+            Reliability::Reliable,
+        )
+    }
+
+    /// Sends outgoing streams as far as their windows allow, and cancels transfers that stalled or failed.
+    fn pump_transfers(&mut self) {
+        let window = self.shared.settings.transfer_window;
+        let timeout = self.shared.settings.connection_timeout;
+        let mut parts = Vec::new();
+        let mut done = Vec::new();
+        let mut failed = Vec::new();
+        for (&id, transfer) in self.outgoing_transfers.iter_mut() {
+            loop {
+                match transfer.next_part(window) {
+                    Ok(Some(part)) => parts.push((id, transfer.dst, part)),
+                    Ok(None) => break,
+                    Err(err) => {
+                        warn!("Could not read stream for transfer {:?}: {}", id, err);
+                        failed.push(id);
+                        break;
+                    }
+                }
+            }
+            if transfer.is_done() {
+                done.push(id);
+            } else if transfer.last_activity.elapsed() > timeout {
+                failed.push(id);
+            }
+        }
+        failed.extend(
+            self.incoming_transfers
+                .iter()
+                .filter(|(_, transfer)| transfer.last_activity.elapsed() > timeout)
+                .map(|(&id, _)| id),
+        );
+        for (id, dst, part) in parts {
+            let msg = match part {
+                TransferPart::Data { offset, data } => {
+                    NetMessageInner::TransferChunk { id, offset, data }
+                }
+                TransferPart::End { len } => NetMessageInner::TransferEnd { id, len },
+            };
+            if failed.contains(&id) {
+                continue;
+            }
+            if let Err(err) = self.send_to(dst, msg, Reliability::Reliable) {
+                warn!("Could not send transfer {:?}: {}", id, err);
+                failed.push(id);
+            }
+        }
+        for id in failed {
+            // A failure to notify the other side must not keep the rest from being cancelled.
+            self.cancel_transfer(id).ok();
+        }
+        for id in done {
+            self.outgoing_transfers.remove(&id);
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) -> Result<(), NetError> {
+        match command {
+            Command::SendStream { id, dst, reader } => {
+                self.outgoing_transfers
+                    .insert(id, OutgoingTransfer::new(dst, reader));
+            }
+            Command::CancelTransfer(id) => self.cancel_transfer(id)?,
+            Command::Disconnect => self.leave(),
+            Command::Shutdown(deadline) => {
+                // Messages sent right before the shutdown may still be in the queue.
+                let shared = self.shared.clone();
+                for msg in shared.outbound_channel.1.try_iter() {
+                    self.queue_outbound(msg);
+                }
+                self.shutdown_deadline = Some(deadline);
+            }
+            Command::Kick { id, message } => {
+                info!("[Host] Kicking peer {}: {}", id, message);
+                self.drop_peer(id, DisconnectReason::Kicked(message))?;
+            }
+            Command::Ban { id, message } => {
+                if let Some(peer) = self.direct_peers.get(&id) {
+                    info!(
+                        "[Host] Banning peer {} ({}): {}",
+                        id,
+                        peer.addr.ip(),
+                        message
+                    );
+                    self.shared.bans.insert(peer.addr.ip());
+                    // Clients that don't send a payload can't be told apart by it.
+                    if !peer.login_payload.is_empty() {
+                        self.shared.identity_bans.insert(peer.login_payload.clone());
+                    }
+                }
+                self.drop_peer(id, DisconnectReason::Banned(message))?;
+            }
+            Command::Accept(addr) => {
+                if let Some((_, payload)) = self.login_requests.remove(&addr) {
+                    self.register_client(addr, payload);
+                } else {
+                    warn!("[Host] No login request from {} to accept", addr);
+                }
+            }
+            Command::Reject { addr, message } => {
+                if self.login_requests.remove(&addr).is_some() {
+                    info!("[Host] Login from {} rejected: {}", addr, message);
+                    self.reject_login(addr, DisconnectReason::Rejected(message));
+                } else {
+                    warn!("[Host] No login request from {} to reject", addr);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn leave(&mut self) {
+        let reason = if self.is_host() {
+            DisconnectReason::HostShutdown
+        } else {
+            DisconnectReason::Left
+        };
+        self.disconnect(reason);
+    }
+
+    /// Says goodbye to every direct peer and stops the reactor.
+    fn disconnect(&mut self, reason: DisconnectReason) {
+        if let Some(my_id) = self.shared.my_id.load() {
+            for (&id, peer) in self.direct_peers.iter_mut() {
+                peer.say_goodbye(&self.shared.socket, my_id, id, reason.clone());
+            }
+        }
+        self.direct_peers.clear();
+        self.shared.set_state(PeerState::Disconnected(reason));
+        self.shared.keep_alive.store(false, SeqCst);
+    }
+
+    /// Tells a direct peer why it is being disconnected and removes it.
+    fn drop_peer(&mut self, id: PeerId, reason: DisconnectReason) -> Result<(), NetError> {
+        if let Some((my_id, peer)) = self.shared.my_id.load().zip(self.direct_peers.get_mut(&id)) {
+            peer.say_goodbye(&self.shared.socket, my_id, id, reason.clone());
+        }
+        self.remove_direct_peer(id, reason)
+    }
+
+    /// Forgets a direct peer, reporting its unconfirmed messages as lost.
+    /// The host also tells everyone else that the peer is gone, while a client that lost the host stops.
+    fn remove_direct_peer(&mut self, id: PeerId, reason: DisconnectReason) -> Result<(), NetError> {
+        let Some(peer) = self.direct_peers.remove(&id) else {
+            return Ok(());
+        };
+        self.decode_failures.remove(&peer.addr);
+        for &handle in peer.unconfirmed.keys() {
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::Lost(handle))?;
+        }
+        if self.is_host() {
+            info!("[Host] Peer {} removed: {:?}", id, reason);
+            self.direct_broadcast(
+                self.shared.my_id.load().unwrap(),
+                ChannelId::default(),
+                NetMessageInner::DelPeer {
+                    id,
+                    reason: reason.clone(),
+                },
+                Reliability::Reliable,
+            )?;
+            if self.shared.settings.max_total_download_rate.is_some() {
+                self.announce_download_limits();
+            }
+        } else if id == PeerId(0) {
+            self.login_retry = None;
+            self.shared
+                .set_state(PeerState::Disconnected(reason.clone()));
+            self.shared.keep_alive.store(false, SeqCst);
+        }
+        self.del_peer(id, reason)
+    }
+
+    fn del_peer(&mut self, id: PeerId, reason: DisconnectReason) -> Result<(), NetError> {
+        self.shared.remote_peers.remove(&id);
+        let failed: Vec<_> = self
+            .outgoing_transfers
+            .iter()
+            .filter(|(_, transfer)| transfer.dst == id)
+            .map(|(&id, _)| id)
+            .chain(
+                self.incoming_transfers
+                    .keys()
+                    .filter(|transfer_id| transfer_id.sender == id)
+                    .copied(),
+            )
+            .collect();
+        for transfer_id in failed {
+            self.outgoing_transfers.remove(&transfer_id);
+            self.incoming_transfers.remove(&transfer_id);
+            self.shared
+                .inbound_channel
+                .0
+                .send(NetworkEvent::TransferFailed(transfer_id))?;
+        }
+        self.shared
+            .inbound_channel
+            .0
+            .send(NetworkEvent::PeerDisconnected { id, reason })?;
+        Ok(())
+    }
+
+    /// Queues a message of the application, reporting a reliable one as lost if it can't be sent, e.g. because the peer has just left.
+    fn queue_outbound(&mut self, msg: OutboundMessage) {
+        let handle = msg.reliability.is_reliable().then_some(msg.handle);
+        if let Err(err) = self.handle_outbound(msg) {
+            info!("Could not send message: {}", err);
+            if let Some(handle) = handle {
+                self.shared
+                    .inbound_channel
+                    .0
+                    .send(NetworkEvent::Lost(handle))
+                    .ok();
+            }
+        }
+    }
+
+    fn handle_outbound(&mut self, msg: OutboundMessage) -> Result<(), NetError> {
+        let dst = msg.dst;
+        if self.is_host() {
+            match dst {
+                Destination::One(id) => {
+                    let mut net_msg = self.wrap_packet(
+                        id,
+                        dst,
+                        msg.channel,
+                        NetMessageInner::Payload { data: msg.data },
                         msg.reliability,
                     )?;
+                    net_msg.set_local_info(msg.handle, msg.deadline);
                     self.direct_send(id, net_msg)?;
                 }
                 Destination::Broadcast => self.direct_broadcast(
                     PeerId(0),
+                    msg.channel,
                     NetMessageInner::Payload { data: msg.data },
                     msg.reliability,
                 )?,
             }
         } else {
-            let net_msg = self.wrap_packet(
+            let mut net_msg = self.wrap_packet(
                 PeerId(0),
                 dst,
+                msg.channel,
                 NetMessageInner::Payload { data: msg.data },
                 msg.reliability,
             )?;
+            net_msg.set_local_info(msg.handle, msg.deadline);
             self.direct_send(PeerId(0), net_msg)?;
         }
         Ok(())
     }
 
+    /// Sends a message to a single peer, through the host if necessary.
+    fn send_to(
+        &mut self,
+        dst: PeerId,
+        msg: NetMessageInner,
+        reliability: Reliability,
+    ) -> Result<(), NetError> {
+        let via = if self.is_host() { dst } else { PeerId(0) };
+        let net_msg = self.wrap_packet(
+            via,
+            Destination::One(dst),
+            ChannelId::default(),
+            msg,
+            reliability,
+        )?;
+        self.direct_send(via, net_msg)
+    }
+
+    /// Tells a direct peer about `Settings::max_download_rate`, or its share of `Settings::max_total_download_rate` if that is lower.
+    fn announce_download_limit(&mut self, peer_id: PeerId) {
+        let settings = &self.shared.settings;
+        let share = settings
+            .max_total_download_rate
+            .map(|rate| rate / self.direct_peers.len().max(1) as u64);
+        if let Some(rate) = settings.max_download_rate.into_iter().chain(share).min() {
+            // Ordered, so that an older share can't replace a newer one.
+            self.send_to(
+                peer_id,
+                NetMessageInner::DownloadLimit { rate },
+                Reliability::ReliableOrdered,
+            )
+            .ok();
+        }
+    }
+
+    fn announce_download_limits(&mut self) {
+        let ids: Vec<_> = self.direct_peers.keys().copied().collect();
+        for id in ids {
+            self.announce_download_limit(id);
+        }
+    }
+
     fn is_host(&self) -> bool {
         self.shared.host_addr.is_none()
     }
 
-    pub fn next_seq_id_for_peer(&self, peer_id: PeerId) -> Result<SeqId, NetError> {
-        Ok(self
-            .direct_peers
-            .get(&peer_id)
-            .or_else(|| {
-                if !self.is_host() {
-                    self.direct_peers.get(&PeerId(0))
-                } else {
-                    None
-                }
-            })
-            .ok_or(NetError::UnknownPeer)?
-            .seq_counter
-            .fetch_add(1, SeqCst))
+    /// Checks that messages for a peer can be sent directly, or through the host for clients.
+    fn check_direct_peer(&self, peer_id: PeerId) -> Result<(), NetError> {
+        if self.direct_peers.contains_key(&peer_id)
+            || !self.is_host() && self.direct_peers.contains_key(&PeerId(0))
+        {
+            Ok(())
+        } else {
+            Err(NetError::UnknownPeer)
+        }
     }
 
     fn run(mut self, inbound_r: Receiver<AddrDatagram>) -> Result<(), Box<dyn Error>> {
         while self.shared.keep_alive.load(SeqCst) {
             select! {
                 recv(inbound_r) -> addr_msg => self.handle_inbound(addr_msg?),
-                recv(self.shared.outbound_channel.1) -> msg => {self.handle_outbound(msg?).ok();}
+                recv(self.shared.outbound_channel.1) -> msg => {
+                    self.queue_outbound(msg?);
+                    // Take everything that is queued, so that it can be packed together.
+                    let shared = self.shared.clone();
+                    for msg in shared.outbound_channel.1.try_iter() {
+                        self.queue_outbound(msg);
+                    }
+                }
+                recv(self.shared.command_channel.1) -> command => {self.handle_command(command?).ok();}
                 default => {thread::sleep(Duration::from_micros(100));}
             }
-            let mut dc = Vec::new();
-            self.direct_peers.retain(|&k, v| {
-                let stays = v.last_seen.elapsed() < self.shared.settings.connection_timeout;
-                if !stays {
-                    dc.push(k);
-                }
-                stays
-            });
-            if self.is_host() {
-                for peer_id in dc {
-                    self.direct_broadcast(
-                        self.shared.my_id.load().unwrap(),
-                        NetMessageInner::DelPeer { id: peer_id },
-                        Reliability::Reliable,
-                    )?;
-                    self.del_peer(peer_id).ok();
-                    info!("[Host] Peer {} removed", peer_id);
-                }
+            if !self.shared.keep_alive.load(SeqCst) {
+                // The rest of the tick would fail the transfers for lack of peers, leaving their readers behind.
+                break;
             }
-            if !self.is_host() && self.direct_peers.is_empty() {
-                self.shared.peer_state.store(PeerState::Disconnected);
-                self.shared.keep_alive.store(false, SeqCst);
+            self.pump_transfers();
+            self.retry_login();
+            let timeout = self.shared.settings.connection_timeout;
+            let expired: Vec<_> = self
+                .login_requests
+                .iter()
+                .filter(|(_, (at, _))| at.elapsed() >= timeout)
+                .map(|(&addr, _)| addr)
+                .collect();
+            for addr in expired {
+                info!("[Host] Login request from {} not answered in time", addr);
+                self.login_requests.remove(&addr);
+                let reason = DisconnectReason::Rejected("Not accepted in time".to_owned());
+                self.reject_login(addr, reason);
             }
-            'peers: for (&id, peer) in self.direct_peers.iter_mut() {
-                let resend_in = Instant::now() + Duration::from_secs(1);
-
-                if let Some(my_id) = self.shared.my_id.load() {
-                    if peer.last_confirm_sent.elapsed() > self.shared.settings.confirm_max_period
-                        || peer.pending_confirms.len()
-                            > self.shared.settings.confirm_max_per_message
+            let grace_period = self.shared.settings.resume_grace_period;
+            let mut lost = Vec::new();
+            let mut timed_out = Vec::new();
+            for (&id, peer) in self.direct_peers.iter() {
+                match peer.suspended {
+                    Some(since)
+                        if grace_period
+                            .is_none_or(|grace_period| since.elapsed() >= grace_period) =>
                     {
-                        peer.last_confirm_sent = Instant::now();
-                        let max_per_message = self.shared.settings.confirm_max_per_message;
-                        let mut confirmed_ids = Vec::with_capacity(max_per_message);
-                        while let Some(confirm) = peer.pending_confirms.pop_front() {
-                            confirmed_ids.push(confirm);
-                            if confirmed_ids.len() == max_per_message {
-                                break;
-                            }
+                        timed_out.push(id);
+                    }
+                    Some(_) => {}
+                    // Until registration, the client waits for `connect_timeout` instead.
+                    None if self.login_retry.is_none() && peer.last_seen.elapsed() >= timeout => {
+                        if grace_period.is_some() {
+                            lost.push(id);
+                        } else {
+                            timed_out.push(id);
                         }
-                        peer.resend_pending.push_front((
-                            Instant::now(),
-                            NetMessageNormal {
-                                src: my_id,
-                                dst: Destination::One(id),
-                                seq_id: peer.seq_counter.fetch_add(1, SeqCst),
-                                reliability: Reliability::Reliable,
-                                inner: NetMessageInner::Confirm { confirmed_ids },
-                            },
-                        ))
                     }
+                    None => {}
+                }
+            }
+            for peer_id in lost {
+                self.suspend_direct_peer(peer_id)?;
+            }
+            for peer_id in timed_out {
+                self.remove_direct_peer(peer_id, DisconnectReason::Timeout)?;
+            }
+            for (&id, peer) in self.direct_peers.iter_mut() {
+                if peer.suspended.is_some() {
+                    continue;
                 }
+                let fragment_timeout = self.shared.settings.fragment_timeout;
+                let fragment_bytes = &mut peer.fragment_bytes;
+                peer.fragments.retain(|_, partial| {
+                    let keep = partial.started.elapsed() < fragment_timeout;
+                    if !keep {
+                        *fragment_bytes -= partial.size;
+                    }
+                    keep
+                });
 
-                while peer
-                    .resend_pending
-                    .front()
-                    .map_or(false, |x| x.0 < Instant::now())
-                {
-                    let (moment, msg) = peer
+                let now = Instant::now();
+                peer.collect_late_confirms();
+                let mut writer = PacketWriter::new(
+                    &self.shared.socket,
+                    peer.addr,
+                    peer.ack(),
+                    peer.mtu.current(),
+                );
+                let mut rate_changed = false;
+                let mut channel_ids: Vec<_> = peer.channels.keys().copied().collect();
+                channel_ids.sort_unstable_by_key(|channel_id| channel_id.0);
+                let start = peer.resend_start % channel_ids.len().max(1);
+                channel_ids.rotate_left(start);
+                'channels: for (n, channel_id) in channel_ids.into_iter().enumerate() {
+                    let channel = peer
+                        .channels
+                        .get_mut(&channel_id)
+                        .expect("Channels are only removed along with the peer");
+                    while channel
                         .resend_pending
-                        .pop_front()
-                        .expect("Checked that deque is not empty");
+                        .front()
+                        .is_some_and(|pending| pending.at < now)
+                    {
+                        let mut pending = channel
+                            .resend_pending
+                            .pop_front()
+                            .expect("Checked that deque is not empty");
+                        let msg = &pending.msg;
 
-                    if !peer.confirmed.contains(&msg.seq_id) {
-                        if !peer.rate_limit.get_token() {
-                            peer.resend_pending.push_front((moment, msg));
-                            continue 'peers;
+                        if !peer.in_flight.contains(&msg.seq_id) {
+                            continue;
+                        }
+                        if out_of_window(peer.newest_sent, msg.seq_id) {
+                            // Messages sent outside of the queues have moved past it, so a resend would be taken for a duplicate.
+                            peer.in_flight.remove(&msg.seq_id);
+                            peer.bulk_in_flight.remove(&msg.seq_id);
+                            let given_up = peer.receipts.remove(&msg.seq_id);
+                            if let Some(handle) =
+                                given_up.filter(|handle| peer.unconfirmed.remove(handle).is_some())
+                            {
+                                self.shared
+                                    .inbound_channel
+                                    .0
+                                    .send(NetworkEvent::Lost(handle))?;
+                            }
+                            continue;
+                        }
+                        if let Some(handle) = msg.handle {
+                            if !peer.unconfirmed.contains_key(&handle) {
+                                // Another fragment of this message has been given up on.
+                                peer.in_flight.remove(&msg.seq_id);
+                                peer.bulk_in_flight.remove(&msg.seq_id);
+                                peer.receipts.remove(&msg.seq_id);
+                                continue;
+                            }
+                            let out_of_resends = msg.reliability != Reliability::ReliableOrdered
+                                && self
+                                    .shared
+                                    .settings
+                                    .max_resends
+                                    .is_some_and(|max| pending.resends >= max);
+                            if out_of_resends || msg.expired(now) {
+                                peer.in_flight.remove(&msg.seq_id);
+                                peer.bulk_in_flight.remove(&msg.seq_id);
+                                let given_up = peer.receipts.remove(&msg.seq_id);
+                                if let Some(handle) = given_up
+                                    .filter(|handle| peer.unconfirmed.remove(handle).is_some())
+                                {
+                                    self.shared
+                                        .inbound_channel
+                                        .0
+                                        .send(NetworkEvent::Lost(handle))?;
+                                }
+                                continue;
+                            }
                         }
-                        peer.resend_pending.push_back((resend_in, msg.clone()));
+                        let resent = NetMessageVariant::Normal(msg.clone());
+                        let bulk = msg.inner.is_bulk();
+                        let upload =
+                            upload_allowed(&mut peer.upload_budget, &mut self.upload_budget, now);
+                        // Tokens are spent per datagram, while each bulk message fills a datagram of its own.
+                        let token = upload
+                            && if bulk {
+                                peer.bulk_congestion.get_token(now)
+                            } else {
+                                writer.fits(&resent) || peer.congestion.get_token(now)
+                            };
+                        if !token {
+                            channel.resend_pending.push_front(pending);
+                            if upload && bulk {
+                                // Only bulk messages are out of tokens, other channels can still resend.
+                                break;
+                            }
+                            // The ones that didn't get a turn go first next time.
+                            peer.resend_start = start + n + 1;
+                            break 'channels;
+                        }
+                        let rtt = peer.rtt.srtt().unwrap_or(peer.rtt.resend_timeout(0));
+                        if bulk {
+                            peer.bulk_congestion.on_loss(now, rtt);
+                        } else {
+                            peer.congestion.on_loss(now, rtt);
+                        }
+                        rate_changed = true;
                         trace!("Sent {:?} to {}", msg, peer.addr);
-                        let datagram = Datagram::try_from(&NetMessageVariant::Normal(msg)).unwrap();
-                        self.shared
-                            .socket
-                            .send_to(&datagram.data[..datagram.size], peer.addr)
-                            .expect("Could not send");
+                        let len = writer.push(resent);
+                        spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                        // Can't tell which one of the sends a confirm would be for, so the message is no longer useful for measuring round-trip time.
+                        peer.sent_at.remove(&msg.seq_id);
+                        pending.resends += 1;
+                        pending.at = now + peer.rtt.resend_timeout(pending.resends);
+                        channel.schedule_resend(pending);
                     }
                 }
 
-                while !peer.outbound_pending.is_empty() && peer.rate_limit.get_token() {
-                    let msg = peer
-                        .outbound_pending
-                        .pop_front()
-                        .expect("Checked that deque is not empty");
+                let mut oldest_in_flight = peer.oldest_in_flight();
+                while let Some(mut msg) = peer.outbound_pending.pop_front() {
+                    let normal = matches!(msg, NetMessageVariant::Normal(_));
                     if let NetMessageVariant::Normal(ref msg) = msg {
-                        if msg.reliability == Reliability::Reliable {
-                            peer.resend_pending.push_back((resend_in, msg.clone()));
+                        if msg.expired(now) {
+                            if let Some(handle) = msg
+                                .handle
+                                .filter(|handle| peer.unconfirmed.remove(handle).is_some())
+                            {
+                                self.shared
+                                    .inbound_channel
+                                    .0
+                                    .send(NetworkEvent::Lost(handle))?;
+                            }
+                            continue;
                         }
                     }
-                    let datagram = Datagram::try_from(&msg).unwrap();
-                    self.shared
-                        .socket
-                        .send_to(&datagram.data[..datagram.size], peer.addr)
-                        .expect("Could not send");
+                    if normal && outruns(oldest_in_flight, peer.seq_counter.load(SeqCst))
+                        || !upload_allowed(&mut peer.upload_budget, &mut self.upload_budget, now)
+                        || !writer.fits(&msg) && !peer.congestion.get_token(now)
+                    {
+                        peer.outbound_pending.push_front(msg);
+                        break;
+                    }
+                    if let NetMessageVariant::Normal(ref mut msg) = msg {
+                        msg.seq_id = peer.seq_counter.fetch_add(1, SeqCst);
+                        if msg.reliability.is_reliable() {
+                            if let Some(handle) = msg.handle {
+                                peer.receipts.insert(msg.seq_id, handle);
+                            }
+                            oldest_in_flight.get_or_insert(msg.seq_id);
+                            peer.sent_at.insert(msg.seq_id, now);
+                            peer.in_flight.insert(msg.seq_id);
+                            let at = now + peer.rtt.resend_timeout(0);
+                            peer.channel(msg.channel).schedule_resend(PendingResend {
+                                at,
+                                resends: 0,
+                                msg: msg.clone(),
+                            });
+                        }
+                    }
+                    let len = writer.push(msg);
+                    spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                }
+
+                while let Some(mut msg) = peer.bulk_pending.pop_front() {
+                    if outruns(oldest_in_flight, peer.seq_counter.load(SeqCst))
+                        || !upload_allowed(&mut peer.upload_budget, &mut self.upload_budget, now)
+                        || !peer.bulk_congestion.get_token(now)
+                    {
+                        peer.bulk_pending.push_front(msg);
+                        break;
+                    }
+                    msg.seq_id = peer.seq_counter.fetch_add(1, SeqCst);
+                    oldest_in_flight.get_or_insert(msg.seq_id);
+                    peer.sent_at.insert(msg.seq_id, now);
+                    peer.in_flight.insert(msg.seq_id);
+                    peer.bulk_in_flight.insert(msg.seq_id);
+                    let at = now + peer.rtt.resend_timeout(0);
+                    peer.channel(msg.channel).schedule_resend(PendingResend {
+                        at,
+                        resends: 0,
+                        msg: msg.clone(),
+                    });
+                    let len = writer.push(NetMessageVariant::Normal(msg));
+                    spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                }
+
+                if let Some((my_id, interval)) = self
+                    .shared
+                    .my_id
+                    .load()
+                    .zip(self.shared.settings.ping_interval)
+                    .filter(|_| now >= peer.next_ping)
+                {
+                    let msg = NetMessageNormal {
+                        src: my_id,
+                        dst: Destination::One(id),
+                        seq_id: peer.seq_counter.fetch_add(1, SeqCst),
+                        channel: ChannelId::default(),
+                        order_id: 0,
+                        handle: None,
+                        deadline: None,
+                        reliability: Reliability::Unreliable,
+                        inner: NetMessageInner::Ping {
+                            time: unix_micros(),
+                        },
+                    };
+                    // Like heartbeats, pings are sent regardless of the budget.
+                    let len = writer.push(NetMessageVariant::Normal(msg));
+                    spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                    peer.next_ping = now + interval;
+                }
+                if let Some(my_id) = self
+                    .shared
+                    .my_id
+                    .load()
+                    .filter(|_| !peer.late_confirms.is_empty())
+                {
+                    let count = peer
+                        .late_confirms
+                        .len()
+                        .min(self.shared.settings.confirm_max_per_message);
+                    let msg = NetMessageNormal {
+                        src: my_id,
+                        dst: Destination::One(id),
+                        seq_id: peer.seq_counter.fetch_add(1, SeqCst),
+                        channel: ChannelId::default(),
+                        order_id: 0,
+                        handle: None,
+                        deadline: None,
+                        reliability: Reliability::Unreliable,
+                        inner: NetMessageInner::Confirm {
+                            confirmed_ids: peer.late_confirms.drain(..count).collect(),
+                        },
+                    };
+                    // If it gets lost, the messages are resent and confirmed again.
+                    let len = writer.push(NetMessageVariant::Normal(msg));
+                    spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                }
+                if writer.is_empty() && peer.needs_heartbeat(now, &self.shared.settings) {
+                    if let Some(my_id) = self.shared.my_id.load() {
+                        let msg = NetMessageNormal {
+                            src: my_id,
+                            dst: Destination::One(id),
+                            seq_id: peer.seq_counter.fetch_add(1, SeqCst),
+                            channel: ChannelId::default(),
+                            order_id: 0,
+                            handle: None,
+                            deadline: None,
+                            reliability: Reliability::Unreliable,
+                            inner: NetMessageInner::Heartbeat,
+                        };
+                        // Sent regardless of the budget, as the connection would time out otherwise.
+                        let len = writer.push(NetMessageVariant::Normal(msg));
+                        spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                    }
+                }
+                writer.flush();
+                if self.shared.settings.mtu_discovery {
+                    let mtu = peer.mtu.current();
+                    let timeout = peer.rtt.resend_timeout(0);
+                    if let Some((my_id, len)) = self
+                        .shared
+                        .my_id
+                        .load()
+                        .zip(peer.mtu.next_probe(now, timeout))
+                    {
+                        let seq_id = peer.seq_counter.fetch_add(1, SeqCst);
+                        let probe = |padding| {
+                            NetMessageVariant::Normal(NetMessageNormal {
+                                src: my_id,
+                                dst: Destination::One(id),
+                                seq_id,
+                                channel: ChannelId::default(),
+                                order_id: 0,
+                                handle: None,
+                                deadline: None,
+                                reliability: Reliability::Unreliable,
+                                inner: NetMessageInner::MtuProbe { padding },
+                            })
+                        };
+                        let overhead = writer.packet_len(&probe(Vec::new()));
+                        let len = writer.send_alone(probe(vec![0; len.saturating_sub(overhead)]));
+                        spend_upload(&mut peer.upload_budget, &mut self.upload_budget, len);
+                        peer.mtu.probe_sent(seq_id, len, now);
+                    }
+                    if peer.mtu.current() < mtu {
+                        peer.resplit_fragments();
+                    }
+                }
+                if rate_changed {
+                    if let Some(mut remote) = self.shared.remote_peers.get_mut(&id) {
+                        remote.send_rate = Some(peer.congestion.rate());
+                    }
+                }
+                if let Some(seq_id) = writer.newest_seq.filter(|&seq_id| {
+                    peer.newest_sent
+                        .is_none_or(|newest| seq_newer(seq_id, newest))
+                }) {
+                    peer.newest_sent = Some(seq_id);
+                }
+                if writer.sent_any {
+                    peer.last_sent = now;
+                    peer.unacked_received = 0;
+                    peer.received_since_ack.clear();
+                    peer.confirm_due = None;
+                }
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                // Suspended peers won't confirm anything until they are back.
+                let flushed = self.direct_peers.values().all(|peer| {
+                    peer.suspended.is_some()
+                        || peer.in_flight.is_empty()
+                            && peer.outbound_pending.is_empty()
+                            && peer.bulk_pending.is_empty()
+                });
+                if flushed || Instant::now() >= deadline {
+                    self.leave();
                 }
             }
         }
+        // Readers that are stuck in a read are left behind, as there is no way to interrupt them.
+        let readers: Vec<_> = self
+            .outgoing_transfers
+            .drain()
+            .map(|(_, transfer)| transfer.stop())
+            .collect();
+        let deadline = Instant::now() + READER_JOIN_TIMEOUT;
+        for reader in readers {
+            join_until(reader, Some(deadline));
+        }
         Ok(())
     }
 
@@ -524,18 +2242,23 @@ impl Reactor {
         shared: Arc<Shared>,
         sender: Sender<(SocketAddr, Datagram)>,
     ) -> Result<(), Box<dyn Error>> {
+        let mut buf = vec![0u8; DATAGRAM_MAX_LEN];
         while shared.keep_alive.load(SeqCst) {
-            let mut buf = [0u8; DATAGRAM_MAX_LEN];
             match shared.socket.recv_from(&mut buf) {
                 Ok((len, addr)) => sender
                     .send((
                         addr,
                         Datagram {
-                            size: len,
-                            data: buf,
+                            data: buf[..len].to_vec(),
                         },
                     ))
                     .map_err(Box::new)?,
+                // Some platforms report ICMP errors of earlier datagrams, e.g. of a login sent before the host was up.
+                Err(err)
+                    if matches!(
+                        err.kind(),
+                        ErrorKind::ConnectionReset | ErrorKind::ConnectionRefused
+                    ) => {}
                 //Err(err)
                 //    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => {
                 //}
@@ -546,9 +2269,19 @@ impl Reactor {
     }
 
     pub(crate) fn start(shared: Arc<Shared>) {
+        let upload_budget = shared.settings.max_total_upload_rate.map(ByteBudget::new);
         let mut me = Reactor {
             shared,
             direct_peers: Default::default(),
+            outgoing_transfers: Default::default(),
+            incoming_transfers: Default::default(),
+            finished_transfers: RingSet::new(1024),
+            upload_budget,
+            login_requests: Default::default(),
+            decode_failures: Default::default(),
+            login_retry: None,
+            session: None,
+            shutdown_deadline: None,
         };
         if !me.is_host() {
             me.direct_peers.insert(
@@ -557,82 +2290,1015 @@ impl Reactor {
                     me.shared
                         .host_addr
                         .expect("Can't be a client without a host addr"),
-                    me.shared.max_packets_per_second,
+                    me.shared.settings.max_upload_rate,
                 ),
             );
-            me.direct_send(PeerId(0), NetMessageVariant::Login).unwrap();
+            let now = Instant::now();
+            me.login_retry = Some(LoginRetry {
+                next: now + LOGIN_RETRY_DELAY,
+                delay: LOGIN_RETRY_DELAY * 2,
+                deadline: Some(now + me.shared.settings.connect_timeout),
+            });
+            me.send_login();
         }
         if me.is_host() {
-            me.shared.peer_state.store(PeerState::Connected);
+            me.shared.set_state(PeerState::Connected);
         }
         let shared_c = Arc::clone(&me.shared);
         let (inbound_s, inbound_r) = bounded(16);
-        thread::spawn(move || {
+        let pipe_thread = thread::spawn(move || {
             let shared_c_2 = Arc::clone(&shared_c);
             if let Err(err) = Self::run_pipe(shared_c_2, inbound_s) {
-                shared_c.keep_alive.store(false, SeqCst);
-                shared_c.peer_state.store(PeerState::Disconnected);
-                error!("Reactor pipe error: {}", err);
+                // Otherwise the error is caused by the reactor having stopped.
+                if shared_c.keep_alive.swap(false, SeqCst) {
+                    shared_c.set_state(PeerState::Disconnected(DisconnectReason::Error));
+                    error!("Reactor pipe error: {}", err);
+                }
             }
         });
+        let shared = Arc::clone(&me.shared);
         let shared_c = Arc::clone(&me.shared);
-        thread::spawn(move || {
+        let reactor_thread = thread::spawn(move || {
             if let Err(err) = me.run(inbound_r) {
                 shared_c.keep_alive.store(false, SeqCst);
-                shared_c.peer_state.store(PeerState::Disconnected);
+                shared_c.set_state(PeerState::Disconnected(DisconnectReason::Error));
                 error!("Reactor error: {}", err);
             }
         });
+        *shared.threads.lock().unwrap() = Some((reactor_thread, pipe_thread));
     }
 
     fn wrap_packet(
         &self,
         id: PeerId,
         dst: Destination,
+        channel: ChannelId,
         msg: NetMessageInner,
         reliability: Reliability,
     ) -> Result<NetMessageVariant, NetError> {
-        let seq_id = self.next_seq_id_for_peer(id)?;
-        Self::wrap_packet_seq_id(
-            self.shared.my_id.load().expect("Should know own id by now"),
-            seq_id,
+        self.check_direct_peer(id)?;
+        Self::wrap_packet_from(
+            self.shared.my_id.load().ok_or(NetError::NotConnected)?,
             dst,
+            channel,
             msg,
             reliability,
         )
     }
 
-    fn wrap_packet_seq_id(
+    /// The sequence id is given once the message is sent, so that ids only get ahead of each other as far as `MAX_SEQ_SPAN` allows.
+    fn wrap_packet_from(
         src: PeerId,
-        seq_id: SeqId,
         dst: Destination,
+        channel: ChannelId,
         msg: NetMessageInner,
         reliability: Reliability,
     ) -> Result<NetMessageVariant, NetError> {
         Ok(NetMessageVariant::Normal(NetMessageNormal {
             src,
             dst,
-            seq_id,
+            seq_id: 0,
+            channel,
+            order_id: 0,
+            handle: None,
+            deadline: None,
             inner: msg,
             reliability,
         }))
     }
 }
 
+/// Whether both the budget of a peer and the total one allow to send more.
+fn upload_allowed(
+    peer_budget: &mut Option<ByteBudget>,
+    total_budget: &mut Option<ByteBudget>,
+    now: Instant,
+) -> bool {
+    [peer_budget, total_budget]
+        .into_iter()
+        .flatten()
+        .all(|budget| budget.allows(now))
+}
+
+/// Whether a message with this sequence id would get further than `MAX_SEQ_SPAN` ahead of the oldest unconfirmed one.
+fn outruns(oldest_in_flight: Option<SeqId>, seq_id: SeqId) -> bool {
+    oldest_in_flight.is_some_and(|oldest| seq_id.wrapping_sub(oldest) as usize >= MAX_SEQ_SPAN)
+}
+
+/// Whether the other side may have moved its `SeqWindow` so far past a message that a resend of it would be taken for a duplicate.
+fn out_of_window(newest_sent: Option<SeqId>, seq_id: SeqId) -> bool {
+    newest_sent.is_some_and(|newest| {
+        !seq_newer(seq_id, newest) && newest.wrapping_sub(seq_id) as usize >= SeqWindow::SIZE
+    })
+}
+
+fn spend_upload(
+    peer_budget: &mut Option<ByteBudget>,
+    total_budget: &mut Option<ByteBudget>,
+    len: usize,
+) {
+    for budget in [peer_budget, total_budget].into_iter().flatten() {
+        budget.spend(len);
+    }
+}
+
+/// Packs messages going to a single peer into as few datagrams as possible.
+struct PacketWriter<'a> {
+    socket: &'a UdpSocket,
+    addr: SocketAddr,
+    max_len: usize,
+    packet: Packet,
+    /// Serialized size of the packet without any messages.
+    empty_size: usize,
+    size: usize,
+    sent_any: bool,
+    /// Newest sequence id of the messages written so far.
+    newest_seq: Option<SeqId>,
+}
+
+impl<'a> PacketWriter<'a> {
+    fn new(socket: &'a UdpSocket, addr: SocketAddr, ack: Option<Ack>, max_len: usize) -> Self {
+        let packet = Packet {
+            ack,
+            messages: Vec::new(),
+        };
+        let empty_size = bincode::serialized_size(&packet).unwrap() as usize;
+        Self {
+            socket,
+            addr,
+            max_len,
+            packet,
+            empty_size,
+            size: empty_size,
+            sent_any: false,
+            newest_seq: None,
+        }
+    }
+
+    fn note_seq_id(&mut self, msg: &NetMessageVariant) {
+        if let NetMessageVariant::Normal(msg) = msg {
+            if self
+                .newest_seq
+                .is_none_or(|newest| seq_newer(msg.seq_id, newest))
+            {
+                self.newest_seq = Some(msg.seq_id);
+            }
+        }
+    }
+
+    /// Whether the message can be added to the datagram that is being filled, without starting a new one.
+    fn fits(&self, msg: &NetMessageVariant) -> bool {
+        !self.packet.messages.is_empty()
+            && self.size + bincode::serialized_size(msg).unwrap() as usize <= self.max_len
+    }
+
+    /// Adds a message, returning its serialized length.
+    fn push(&mut self, msg: NetMessageVariant) -> usize {
+        if !self.fits(&msg) {
+            self.flush();
+        }
+        let len = bincode::serialized_size(&msg).unwrap() as usize;
+        self.size += len;
+        self.note_seq_id(&msg);
+        self.packet.messages.push(msg);
+        len
+    }
+
+    /// Sends the datagram that is being filled, if there is one.
+    fn flush(&mut self) {
+        if self.packet.messages.is_empty() {
+            return;
+        }
+        let datagram = Datagram::try_from(&self.packet).unwrap();
+        // With Don't Fragment set, the datagram can be too large for a path that has changed, until discovery finds that out.
+        if let Err(err) = self.socket.send_to(&datagram.data, self.addr) {
+            warn!("Could not send to {}: {}", self.addr, err);
+        }
+        self.packet.messages.clear();
+        self.size = self.empty_size;
+        self.sent_any = true;
+    }
+
+    /// Length of a datagram with just this message.
+    fn packet_len(&self, msg: &NetMessageVariant) -> usize {
+        self.empty_size + bincode::serialized_size(msg).unwrap() as usize
+    }
+
+    /// Sends a message in a datagram of its own, returning the length of the datagram.
+    /// Errors are ignored, as they happen when the datagram is too large to be sent at all, which has to be found out by probing anyway.
+    fn send_alone(&mut self, msg: NetMessageVariant) -> usize {
+        self.note_seq_id(&msg);
+        let packet = Packet {
+            ack: self.packet.ack,
+            messages: vec![msg],
+        };
+        let datagram = Datagram::try_from(&packet).unwrap();
+        self.socket.send_to(&datagram.data, self.addr).ok();
+        self.sent_any = true;
+        datagram.data.len()
+    }
+
+    /// Whether anything has been written, including the messages that are not flushed yet.
+    fn is_empty(&self) -> bool {
+        !self.sent_any && self.packet.messages.is_empty()
+    }
+}
+
 impl DirectPeer {
-    fn new(incoming_addr: SocketAddr, rate_limit: usize) -> DirectPeer {
+    fn new(incoming_addr: SocketAddr, max_upload_rate: Option<u64>) -> DirectPeer {
         let now = Instant::now();
         DirectPeer {
             addr: incoming_addr,
             outbound_pending: Default::default(),
-            resend_pending: Default::default(),
-            confirmed: RingSet::new(1024),
-            rate_limit: RateLimiter::new(rate_limit, Duration::from_secs(1)),
+            bulk_pending: Default::default(),
+            in_flight: HashSet::new(),
+            congestion: CongestionControl::new(),
+            bulk_in_flight: HashSet::new(),
+            bulk_congestion: CongestionControl::new(),
+            upload_budget: max_upload_rate.map(ByteBudget::new),
+            mtu: MtuProber::new(DATAGRAM_DEFAULT_LEN, DATAGRAM_MAX_LEN),
             seq_counter: AtomicU16::new(0),
-            recent_seq: RingSet::new(1024),
-            pending_confirms: VecDeque::new(),
-            last_confirm_sent: now,
+            newest_sent: None,
+            recent_seq: SeqWindow::new(),
+            channels: HashMap::new(),
+            fragment_counter: 0,
+            fragments: HashMap::new(),
+            fragment_bytes: 0,
+            held_bytes: 0,
+            resend_start: 0,
+            receipts: HashMap::new(),
+            unconfirmed: HashMap::new(),
+            unacked_received: 0,
+            received_since_ack: Vec::new(),
+            late_confirms: Vec::new(),
+            confirm_due: None,
+            last_sent: now,
+            sent_at: HashMap::new(),
+            rtt: RttEstimator::new(),
             last_seen: now,
+            latency: LatencyTracker::default(),
+            next_ping: now,
+            session_token: None,
+            login_payload: Vec::new(),
+            suspended: None,
+        }
+    }
+
+    fn mark_received(&mut self, seq_id: SeqId) {
+        self.recent_seq.insert(seq_id);
+        self.unacked_received += 1;
+        self.last_seen = Instant::now();
+    }
+
+    /// Confirms for the messages received recently.
+    fn ack(&self) -> Option<Ack> {
+        let latest = self.recent_seq.latest()?;
+        let bits = (0..Ack::BITS)
+            .filter(|&n| {
+                self.recent_seq
+                    .contains(latest.wrapping_sub(n as SeqId + 1))
+            })
+            .fold(0, |bits, n| bits | 1 << n);
+        Some(Ack { latest, bits })
+    }
+
+    /// Whether the ack sent with every datagram can still confirm a received message.
+    fn ack_covers(&self, seq_id: SeqId) -> bool {
+        self.recent_seq
+            .latest()
+            .is_some_and(|latest| (latest.wrapping_sub(seq_id) as u32) <= Ack::BITS)
+    }
+
+    /// Moves the received messages that the ack can't reach anymore, e.g. because many of them arrived between two sent datagrams, over to `late_confirms`.
+    fn collect_late_confirms(&mut self) {
+        let Some(latest) = self.recent_seq.latest() else {
+            return;
+        };
+        let late_confirms = &mut self.late_confirms;
+        self.received_since_ack.retain(|&seq_id| {
+            let covered = latest.wrapping_sub(seq_id) as u32 <= Ack::BITS;
+            if !covered {
+                late_confirms.push(seq_id);
+            }
+            covered
+        });
+    }
+
+    /// Whether a datagram has to be sent even if there are no messages, to confirm received ones or to keep the connection alive.
+    fn needs_heartbeat(&self, now: Instant, settings: &Settings) -> bool {
+        now - self.last_sent > settings.confirm_max_period
+            || self.confirm_due.is_some_and(|due| due <= now)
+            // Older messages would no longer fit into the ack.
+            || self.unacked_received >= Ack::BITS / 2
+    }
+
+    /// Marks a message as confirmed, returning its handle if it was the last unconfirmed part of that message.
+    fn confirm(&mut self, seq_id: SeqId) -> Option<MessageHandle> {
+        // Acks can mention ids of messages that were confirmed before, or weren't sent yet after wraparound.
+        if !self.in_flight.remove(&seq_id) {
+            return None;
+        }
+        let sample = self
+            .sent_at
+            .remove(&seq_id)
+            .map(|sent_at| sent_at.elapsed());
+        if let Some(sample) = sample {
+            self.rtt.add_sample(sample);
+        }
+        let congestion = if self.bulk_in_flight.remove(&seq_id) {
+            &mut self.bulk_congestion
+        } else {
+            &mut self.congestion
+        };
+        congestion.on_ack(Instant::now(), sample);
+        let handle = self.receipts.remove(&seq_id)?;
+        let remaining = self.unconfirmed.get_mut(&handle)?;
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.unconfirmed.remove(&handle);
+            Some(handle)
+        } else {
+            None
+        }
+    }
+
+    /// Sequence id of the message that has been waiting for a confirm the longest.
+    fn oldest_in_flight(&self) -> Option<SeqId> {
+        let next = self.seq_counter.load(SeqCst);
+        self.in_flight
+            .iter()
+            .copied()
+            .max_by_key(|&seq_id| next.wrapping_sub(seq_id))
+    }
+
+    /// Sends a goodbye right away, bypassing the queues.
+    fn say_goodbye(
+        &mut self,
+        socket: &UdpSocket,
+        my_id: PeerId,
+        id: PeerId,
+        reason: DisconnectReason,
+    ) {
+        let msg = NetMessageNormal {
+            src: my_id,
+            dst: Destination::One(id),
+            seq_id: self.seq_counter.fetch_add(1, SeqCst),
+            channel: ChannelId::default(),
+            order_id: 0,
+            handle: None,
+            deadline: None,
+            reliability: Reliability::Unreliable,
+            inner: NetMessageInner::Goodbye { reason },
+        };
+        let mut writer = PacketWriter::new(socket, self.addr, self.ack(), self.mtu.current());
+        // There won't be anyone to resend it, so a few copies are sent in case some of them get lost.
+        for _ in 0..GOODBYE_COPIES {
+            writer.send_alone(NetMessageVariant::Normal(msg.clone()));
+        }
+    }
+
+    /// Applies a limit asked for by the peer on top of `Settings::max_upload_rate`.
+    fn limit_upload(&mut self, rate: u64, max_upload_rate: Option<u64>) {
+        let rate = max_upload_rate.map_or(rate, |max| max.min(rate));
+        match &mut self.upload_budget {
+            Some(budget) => budget.set_rate(rate),
+            None => self.upload_budget = Some(ByteBudget::new(rate)),
+        }
+    }
+
+    fn channel(&mut self, id: ChannelId) -> &mut ChannelState {
+        self.channels.entry(id).or_default()
+    }
+
+    /// Splits a message with a payload that doesn't fit into a datagram into fragments.
+    /// Other messages are returned as is.
+    fn fragment(&mut self, msg: NetMessageNormal) -> Vec<NetMessageNormal> {
+        let fragment_len = self.fragment_len();
+        let data = match msg.inner {
+            NetMessageInner::Payload { data } if data.len() > fragment_len => data,
+            inner => return vec![NetMessageNormal { inner, ..msg }],
+        };
+        let group = self.fragment_counter;
+        self.fragment_counter = self.fragment_counter.wrapping_add(1);
+        let count = u16::try_from(data.len().div_ceil(fragment_len))
+            .expect("Message length is checked when sending");
+        data.chunks(fragment_len)
+            .enumerate()
+            .map(|(index, chunk)| NetMessageNormal {
+                src: msg.src,
+                dst: msg.dst.clone(),
+                seq_id: msg.seq_id,
+                channel: msg.channel,
+                order_id: msg.order_id,
+                reliability: msg.reliability,
+                handle: msg.handle,
+                deadline: msg.deadline,
+                inner: NetMessageInner::Fragment {
+                    group,
+                    index: index as u16,
+                    count,
+                    data: chunk.to_vec(),
+                },
+            })
+            .collect()
+    }
+
+    /// Room for data in a datagram of the current size.
+    fn fragment_len(&self) -> usize {
+        // Same amount of space is left for headers as with the default datagram size.
+        self.mtu.current() - (DATAGRAM_DEFAULT_LEN - MAX_MESSAGE_LEN)
+    }
+
+    /// Splits up the fragments that were made for a larger datagram size than the current one, as they no longer get through.
+    /// Ones that have been sent already are sent again as pieces, under new sequence ids.
+    fn resplit_fragments(&mut self) {
+        let fragment_len = self.fragment_len();
+        let oversized = |msg: &NetMessageNormal| matches!(&msg.inner, NetMessageInner::Fragment { data, .. } if data.len() > fragment_len);
+        let mut sent = Vec::new();
+        for channel in self.channels.values_mut() {
+            let (resplit, keep) = channel
+                .resend_pending
+                .drain(..)
+                .partition(|pending| oversized(&pending.msg));
+            channel.resend_pending = keep;
+            sent.extend(resplit);
+        }
+        let mut outbound = VecDeque::new();
+        for PendingResend { msg, .. } in sent {
+            // Confirmed ones are only removed from the queue once their turn comes.
+            if self.in_flight.remove(&msg.seq_id) {
+                self.receipts.remove(&msg.seq_id);
+                self.sent_at.remove(&msg.seq_id);
+                outbound.extend(self.refragment(msg));
+            }
+        }
+        for msg in mem::take(&mut self.outbound_pending) {
+            match msg {
+                NetMessageVariant::Normal(msg) if oversized(&msg) => {
+                    outbound.extend(self.refragment(msg))
+                }
+                msg => outbound.push_back(msg),
+            }
+        }
+        self.outbound_pending = outbound;
+    }
+
+    /// Splits a fragment into `Refragment` pieces that fit into a datagram of the current size.
+    fn refragment(&mut self, msg: NetMessageNormal) -> Vec<NetMessageVariant> {
+        let fragment_len = self.fragment_len();
+        let data = bincode::serialize(&msg).unwrap();
+        let count = data.len().div_ceil(fragment_len) as u16;
+        if let Some(handle) = msg.handle.filter(|_| msg.reliability.is_reliable()) {
+            // The pieces are confirmed instead of the fragment.
+            let Some(unconfirmed) = self.unconfirmed.get_mut(&handle) else {
+                // Given up on already.
+                return Vec::new();
+            };
+            *unconfirmed += count as usize - 1;
+        }
+        let group = self.fragment_counter;
+        self.fragment_counter = self.fragment_counter.wrapping_add(1);
+        data.chunks(fragment_len)
+            .enumerate()
+            .map(|(index, chunk)| {
+                NetMessageVariant::Normal(NetMessageNormal {
+                    src: msg.src,
+                    dst: msg.dst.clone(),
+                    seq_id: 0,
+                    channel: msg.channel,
+                    // Ordering and sequencing apply to the fragment once it is put back together.
+                    order_id: 0,
+                    reliability: if msg.reliability.is_reliable() {
+                        Reliability::Reliable
+                    } else {
+                        Reliability::Unreliable
+                    },
+                    handle: msg.handle,
+                    deadline: msg.deadline,
+                    inner: NetMessageInner::Refragment {
+                        group,
+                        index: index as u16,
+                        count,
+                        data: chunk.to_vec(),
+                    },
+                })
+            })
+            .collect()
+    }
+}
+
+impl ChannelState {
+    /// Queues a resend. The queue is kept ordered by time, as the timeouts vary with the round-trip time.
+    fn schedule_resend(&mut self, pending: PendingResend) {
+        let index = self
+            .resend_pending
+            .partition_point(|other| other.at <= pending.at);
+        self.resend_pending.insert(index, pending);
+    }
+}
+
+impl Default for ChannelState {
+    fn default() -> Self {
+        Self {
+            resend_pending: VecDeque::new(),
+            order_counter: 0,
+            ordered_inbound: ReorderBuffer::new(1024),
+            sequence_counter: 0,
+            last_sequenced: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, UdpSocket}, time::{Duration, Instant}
+    };
+
+    use super::{
+        Datagram, DirectPeer, MtuProber, NetMessageInner, NetMessageNormal, NetMessageVariant, Packet, PacketWriter, PendingResend, Reactor, Reliability, Session, Settings, DATAGRAM_DEFAULT_LEN, DATAGRAM_MAX_LEN, MAX_DECODE_FAILURES, PROTOCOL_VERSION
+    };
+    use crate::{
+        reactor::Destination, ChannelId, DisconnectReason, Message, MessageHandle, NetworkEvent, Peer, PeerId, SeqId
+    };
+
+    #[test]
+    fn late_confirms() {
+        let mut peer = DirectPeer::new("127.0.0.1:0".parse().unwrap(), None);
+        for seq_id in 0..40 {
+            peer.mark_received(seq_id);
+            peer.received_since_ack.push(seq_id);
+        }
+        peer.collect_late_confirms();
+        assert_eq!(peer.late_confirms, (0..7).collect::<Vec<_>>());
+        assert!(!peer.ack_covers(6));
+        assert!(peer.ack_covers(7));
+    }
+
+    #[test_log::test]
+    fn repeated_login() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let login = NetMessageVariant::Login {
+            version: PROTOCOL_VERSION,
+            payload: Vec::new(),
+            session: None,
+        };
+        // Id and sequence id of the `RegDone` answering each login.
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN).send_alone(login.clone());
+            let reply = loop {
+                let mut buf = vec![0; DATAGRAM_MAX_LEN];
+                let len = socket.recv(&mut buf).unwrap();
+                buf.truncate(len);
+                let packet = Packet::try_from(Datagram { data: buf }).unwrap();
+                let reg_done = packet.messages.into_iter().find_map(|msg| match msg {
+                    NetMessageVariant::Normal(NetMessageNormal {
+                        inner: NetMessageInner::RegDone { .. },
+                        dst: Destination::One(id),
+                        seq_id,
+                        ..
+                    }) => Some((id, seq_id)),
+                    _ => None,
+                });
+                // A resend of an earlier one has the same sequence id, only a new one answers the login.
+                if let Some(reply) = reg_done.filter(|reply| !replies.contains(reply)) {
+                    break reply;
+                }
+            };
+            replies.push(reply);
+        }
+        assert_eq!(replies[0].0, replies[1].0);
+        // Registered just once.
+        let connected = host
+            .recv()
+            .filter(|event| matches!(event, NetworkEvent::PeerConnected(_)))
+            .count();
+        assert_eq!(connected, 1);
+        assert_eq!(host.iter_peer_ids().count(), 2);
+        assert!(host.iter_peer_ids().any(|id| id == replies[0].0));
+    }
+
+    /// Logs in to the host at `addr` from a bare socket, returning the session it got.
+    fn login(socket: &UdpSocket, addr: SocketAddr) -> Session {
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let login = NetMessageVariant::Login {
+            version: PROTOCOL_VERSION,
+            payload: Vec::new(),
+            session: None,
+        };
+        PacketWriter::new(socket, addr, None, DATAGRAM_DEFAULT_LEN).send_alone(login);
+        loop {
+            let mut buf = vec![0; DATAGRAM_MAX_LEN];
+            let len = socket.recv(&mut buf).unwrap();
+            buf.truncate(len);
+            let packet = Packet::try_from(Datagram { data: buf }).unwrap();
+            let reg_done = packet.messages.into_iter().find_map(|msg| match msg {
+                NetMessageVariant::Normal(NetMessageNormal {
+                    inner: NetMessageInner::RegDone { token, .. },
+                    dst: Destination::One(id),
+                    ..
+                }) => Some(Session { id, token }),
+                _ => None,
+            });
+            if let Some(session) = reg_done {
+                return session;
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn ordered_channels_independent() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let id = login(&socket, addr).id;
+        let send = |seq_id, channel, order_id| {
+            let msg = NetMessageNormal {
+                src: id,
+                dst: Destination::One(PeerId(0)),
+                seq_id,
+                channel: ChannelId(channel),
+                order_id,
+                reliability: Reliability::ReliableOrdered,
+                inner: NetMessageInner::Payload {
+                    data: vec![channel, order_id as u8],
+                },
+                handle: None,
+                deadline: None,
+            };
+            PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                .send_alone(NetMessageVariant::Normal(msg));
+        };
+        let recv_message = || loop {
+            match host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for a message")
+            {
+                NetworkEvent::Message(msg) => break msg,
+                _ => continue,
+            }
+        };
+
+        // The first message of channel 1 is held back, which must not hold up channel 2.
+        send(0, 1, 1);
+        send(1, 2, 0);
+        assert_eq!(
+            recv_message(),
+            Message {
+                channel: ChannelId(2),
+                data: vec![2, 0]
+            }
+        );
+        send(2, 1, 0);
+        assert_eq!(recv_message().data, vec![1, 0]);
+        assert_eq!(recv_message().data, vec![1, 1]);
+    }
+
+    #[test_log::test]
+    fn reorder_buffer_limit() {
+        let settings = Settings {
+            max_fragment_buffer: 2000,
+            ..Default::default()
+        };
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), Some(settings)).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let id = login(&socket, addr).id;
+        let send = |order_id: SeqId, copy: u8| {
+            let msg = NetMessageNormal {
+                src: id,
+                dst: Destination::One(PeerId(0)),
+                seq_id: order_id,
+                channel: ChannelId::default(),
+                order_id,
+                reliability: Reliability::ReliableOrdered,
+                inner: NetMessageInner::Payload {
+                    data: [order_id as u8, copy].repeat(250),
+                },
+                handle: None,
+                deadline: None,
+            };
+            PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                .send_alone(NetMessageVariant::Normal(msg));
+        };
+        let recv_message = || loop {
+            match host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for a message")
+            {
+                NetworkEvent::Message(msg) => break (msg.data[0], msg.data[1]),
+                _ => continue,
+            }
+        };
+
+        // Only three of them fit into the buffer while the first one is missing.
+        for order_id in 1..10 {
+            send(order_id, 0);
         }
+        send(0, 0);
+        for order_id in 0..4 {
+            assert_eq!(recv_message(), (order_id, 0));
+        }
+        // The rest weren't taken, so the resent copies are the ones delivered.
+        for order_id in 4..10 {
+            send(order_id, 1);
+        }
+        for order_id in 4..10 {
+            assert_eq!(recv_message(), (order_id, 1));
+        }
+    }
+
+    #[test_log::test]
+    fn undecodable_datagrams() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let id = login(&socket, addr).id;
+        let recv_event = |expected: fn(&NetworkEvent) -> bool| loop {
+            let event = host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for an event");
+            if expected(&event) {
+                break event;
+            }
+            assert!(
+                !matches!(event, NetworkEvent::PeerDisconnected { .. }),
+                "Peer dropped too early"
+            );
+        };
+
+        // A few corrupted datagrams in between valid ones are just discarded.
+        for seq_id in 0..2 {
+            for _ in 0..MAX_DECODE_FAILURES - 1 {
+                socket.send_to(b"nonsense", addr).unwrap();
+            }
+            let msg = NetMessageNormal {
+                src: id,
+                dst: Destination::One(PeerId(0)),
+                seq_id,
+                channel: ChannelId::default(),
+                order_id: 0,
+                reliability: Reliability::Unreliable,
+                inner: NetMessageInner::Payload { data: vec![1] },
+                handle: None,
+                deadline: None,
+            };
+            PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                .send_alone(NetMessageVariant::Normal(msg));
+            recv_event(|event| matches!(event, NetworkEvent::Message(_)));
+        }
+
+        // Only a peer that keeps sending them is dropped.
+        for _ in 0..MAX_DECODE_FAILURES {
+            socket.send_to(b"nonsense", addr).unwrap();
+        }
+        assert_eq!(
+            recv_event(|event| matches!(event, NetworkEvent::PeerDisconnected { .. })),
+            NetworkEvent::PeerDisconnected {
+                id,
+                reason: DisconnectReason::ProtocolError
+            }
+        );
+    }
+
+    #[test_log::test]
+    fn resume_live_session() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let session = login(&socket, addr);
+        let send_payload = |seq_id| {
+            let msg = NetMessageNormal {
+                src: session.id,
+                dst: Destination::One(PeerId(0)),
+                seq_id,
+                channel: ChannelId::default(),
+                order_id: 0,
+                reliability: Reliability::Unreliable,
+                inner: NetMessageInner::Payload { data: vec![1] },
+                handle: None,
+                deadline: None,
+            };
+            PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                .send_alone(NetMessageVariant::Normal(msg));
+        };
+        send_payload(0);
+
+        // Someone else tries to take over the session while the client is still there.
+        let other = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let resume = NetMessageVariant::Login {
+            version: PROTOCOL_VERSION,
+            payload: Vec::new(),
+            session: Some(session),
+        };
+        PacketWriter::new(&other, addr, None, DATAGRAM_DEFAULT_LEN).send_alone(resume);
+        // Handled after the resume attempt, as it's sent later to the same socket.
+        send_payload(1);
+        let mut payloads = 0;
+        while payloads < 2 {
+            match host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for a message")
+            {
+                NetworkEvent::Message(_) => payloads += 1,
+                NetworkEvent::PeerResumed(_) => panic!("Live session resumed"),
+                _ => {}
+            }
+        }
+
+        // Messages to the client still go to its original address.
+        host.send(session.id, vec![2], Reliability::Reliable)
+            .unwrap();
+        loop {
+            let mut buf = vec![0; DATAGRAM_MAX_LEN];
+            let len = socket.recv(&mut buf).unwrap();
+            buf.truncate(len);
+            let packet = Packet::try_from(Datagram { data: buf }).unwrap();
+            if packet.messages.iter().any(|msg| {
+                matches!(msg, NetMessageVariant::Normal(NetMessageNormal {
+                    inner: NetMessageInner::Payload { data },
+                    ..
+                }) if *data == vec![2])
+            }) {
+                break;
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn stale_sequenced_dropped() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let id = login(&socket, addr).id;
+        let send = |seq_id, order_id| {
+            let msg = NetMessageNormal {
+                src: id,
+                dst: Destination::One(PeerId(0)),
+                seq_id,
+                channel: ChannelId::default(),
+                order_id,
+                reliability: Reliability::UnreliableSequenced,
+                inner: NetMessageInner::Payload {
+                    data: vec![order_id as u8],
+                },
+                handle: None,
+                deadline: None,
+            };
+            PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                .send_alone(NetMessageVariant::Normal(msg));
+        };
+        let recv_message = || loop {
+            match host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for a message")
+            {
+                NetworkEvent::Message(msg) => break msg,
+                _ => continue,
+            }
+        };
+
+        // The late one arrives between two newer ones and is dropped.
+        send(0, 5);
+        send(1, 3);
+        send(2, 6);
+        assert_eq!(recv_message().data, vec![5]);
+        assert_eq!(recv_message().data, vec![6]);
+    }
+
+    #[test_log::test]
+    fn forged_goodbye_ignored() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let id = login(&socket, addr).id;
+        let forger = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let send = |socket: &UdpSocket, seq_id, inner| {
+            let msg = NetMessageNormal {
+                src: id,
+                dst: Destination::One(PeerId(0)),
+                seq_id,
+                channel: ChannelId::default(),
+                order_id: 0,
+                reliability: Reliability::Unreliable,
+                inner,
+                handle: None,
+                deadline: None,
+            };
+            PacketWriter::new(socket, addr, None, DATAGRAM_DEFAULT_LEN)
+                .send_alone(NetMessageVariant::Normal(msg));
+        };
+
+        send(
+            &forger,
+            0,
+            NetMessageInner::Goodbye {
+                reason: DisconnectReason::Left,
+            },
+        );
+        send(&socket, 1, NetMessageInner::Payload { data: vec![1] });
+        // Still connected once the message sent after the goodbye arrives.
+        loop {
+            match host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for a message")
+            {
+                NetworkEvent::Message(msg) => break assert_eq!(msg.data, vec![1]),
+                NetworkEvent::PeerDisconnected { .. } => panic!("Disconnected by a forged goodbye"),
+                _ => continue,
+            }
+        }
+        assert!(host.iter_peer_ids().any(|peer_id| peer_id == id));
+    }
+
+    #[test_log::test]
+    fn resplit_fragments() {
+        let host = Peer::host("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = host.shared.socket.local_addr().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let id = login(&socket, addr).id;
+        let mut peer = DirectPeer::new(addr, None);
+        // A larger size got through before.
+        peer.mtu.probe_sent(0, 5000, Instant::now());
+        peer.mtu.on_ack(0, Instant::now());
+        let handle = MessageHandle(1);
+        let data: Vec<u8> = (0..12_000).map(|i| i as u8).collect();
+        let msg = NetMessageNormal {
+            src: id,
+            dst: Destination::One(PeerId(0)),
+            seq_id: 0,
+            channel: ChannelId::default(),
+            order_id: 0,
+            reliability: Reliability::ReliableOrdered,
+            inner: NetMessageInner::Payload { data: data.clone() },
+            handle: Some(handle),
+            deadline: None,
+        };
+        Reactor::direct_send_peer(&mut peer, NetMessageVariant::Normal(msg)).unwrap();
+        assert_eq!(peer.unconfirmed[&handle], 3);
+        // The first fragment has been sent, but didn't get through.
+        let Some(NetMessageVariant::Normal(mut sent)) = peer.outbound_pending.pop_front() else {
+            panic!("Fragment expected");
+        };
+        sent.seq_id = 0;
+        peer.in_flight.insert(0);
+        peer.channel(sent.channel).schedule_resend(PendingResend {
+            at: Instant::now(),
+            resends: 0,
+            msg: sent,
+        });
+
+        peer.mtu = MtuProber::new(DATAGRAM_DEFAULT_LEN, DATAGRAM_MAX_LEN);
+        peer.resplit_fragments();
+        assert!(!peer.in_flight.contains(&0));
+        assert!(peer.channel(ChannelId::default()).resend_pending.is_empty());
+        assert_eq!(peer.unconfirmed[&handle], peer.outbound_pending.len());
+        let mut writer = PacketWriter::new(&socket, addr, None, DATAGRAM_DEFAULT_LEN);
+        for (seq_id, mut msg) in (1..).zip(peer.outbound_pending.drain(..)) {
+            assert!(writer.packet_len(&msg) <= DATAGRAM_DEFAULT_LEN);
+            if let NetMessageVariant::Normal(msg) = &mut msg {
+                msg.seq_id = seq_id;
+            }
+            writer.send_alone(msg);
+        }
+        let received = loop {
+            match host
+                .shared
+                .inbound_channel
+                .1
+                .recv_timeout(Duration::from_secs(5))
+                .expect("Timed out waiting for a message")
+            {
+                NetworkEvent::Message(msg) => break msg.data,
+                _ => continue,
+            }
+        };
+        assert_eq!(received, data);
     }
 }
@@ -0,0 +1,240 @@
+use std::{
+    collections::BTreeMap, io::{self, ErrorKind, Read}, thread::{self, JoinHandle}, time::Instant
+};
+
+use crossbeam::channel::{bounded, Receiver, Sender, TryRecvError};
+
+use crate::{PeerId, MAX_MESSAGE_LEN};
+
+/// Chunks read ahead of what the window allows to send.
+const READ_AHEAD: usize = 16;
+
+/// Next thing that has to be sent for an outgoing transfer.
+pub(crate) enum TransferPart {
+    Data { offset: u64, data: Vec<u8> },
+    End { len: u64 },
+}
+
+/// Sending side of a stream transfer.
+/// The stream is read by a helper thread, so that a slow reader doesn't hold up the reactor.
+pub(crate) struct OutgoingTransfer {
+    pub dst: PeerId,
+    /// Chunks of the stream, an empty one marks its end.
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    reader: JoinHandle<()>,
+    sent: u64,
+    acked: u64,
+    finished: bool,
+    pub last_activity: Instant,
+}
+
+impl OutgoingTransfer {
+    pub fn new(dst: PeerId, reader: Box<dyn Read + Send>) -> Self {
+        let (chunks_s, chunks) = bounded(READ_AHEAD);
+        let reader = thread::spawn(move || read_stream(reader, chunks_s));
+        Self {
+            dst,
+            chunks,
+            reader,
+            sent: 0,
+            acked: 0,
+            finished: false,
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Takes the next part of the stream if it has been read already, unless there are `window` bytes waiting for an ack.
+    pub fn next_part(&mut self, window: usize) -> io::Result<Option<TransferPart>> {
+        if self.finished || self.sent - self.acked >= window as u64 {
+            return Ok(None);
+        }
+        let data = match self.chunks.try_recv() {
+            Ok(chunk) => chunk?,
+            Err(TryRecvError::Empty) => return Ok(None),
+            Err(TryRecvError::Disconnected) => {
+                return Err(io::Error::other("Stream reader has stopped"))
+            }
+        };
+        if data.is_empty() {
+            self.finished = true;
+            return Ok(Some(TransferPart::End { len: self.sent }));
+        }
+        let offset = self.sent;
+        self.sent += data.len() as u64;
+        Ok(Some(TransferPart::Data { offset, data }))
+    }
+
+    pub fn ack(&mut self, received: u64) {
+        if received > self.acked && received <= self.sent {
+            self.acked = received;
+            self.last_activity = Instant::now();
+        }
+    }
+
+    /// Whether the whole stream has been read and acknowledged by the receiver.
+    pub fn is_done(&self) -> bool {
+        self.finished && self.acked == self.sent
+    }
+
+    /// Drops the transfer, returning the thread that reads the stream. It stops once its current read returns.
+    pub fn stop(self) -> JoinHandle<()> {
+        self.reader
+    }
+}
+
+/// Reads `reader` until it ends or fails, or the transfer is dropped.
+fn read_stream(mut reader: Box<dyn Read + Send>, chunks: Sender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut data = vec![0; MAX_MESSAGE_LEN];
+        let chunk = loop {
+            match reader.read(&mut data) {
+                Ok(len) => {
+                    data.truncate(len);
+                    break Ok(data);
+                }
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => break Err(err),
+            }
+        };
+        let last = !chunk.as_ref().is_ok_and(|data| !data.is_empty());
+        if chunks.send(chunk).is_err() || last {
+            return;
+        }
+    }
+}
+
+/// Receiving side of a stream transfer.
+pub(crate) struct IncomingTransfer {
+    data: Vec<u8>,
+    pending: BTreeMap<u64, Vec<u8>>,
+    len: Option<u64>,
+    size: u64,
+    last_acked: u64,
+    pub last_activity: Instant,
+}
+
+impl IncomingTransfer {
+    pub fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            pending: BTreeMap::new(),
+            len: None,
+            size: 0,
+            last_acked: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    /// Stores a chunk, returning false if it would end past the largest possible stream, in which case it's ignored.
+    pub fn add(&mut self, offset: u64, data: Vec<u8>) -> bool {
+        let Some(end) = offset.checked_add(data.len() as u64) else {
+            return false;
+        };
+        self.last_activity = Instant::now();
+        self.size = self.size.max(end);
+        if offset >= self.received() {
+            self.pending.insert(offset, data);
+        }
+        while let Some(data) = self.pending.remove(&self.received()) {
+            self.data.extend_from_slice(&data);
+        }
+        true
+    }
+
+    pub fn set_len(&mut self, len: u64) {
+        self.last_activity = Instant::now();
+        self.size = self.size.max(len);
+        self.len = Some(len);
+    }
+
+    /// Amount of bytes received without gaps.
+    pub fn received(&self) -> u64 {
+        self.data.len() as u64
+    }
+
+    /// Size of the stream as far as it is known.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.len == Some(self.received())
+    }
+
+    /// Returns the amount of received bytes if enough of them arrived since the last ack.
+    pub fn take_ack(&mut self, window: usize) -> Option<u64> {
+        let received = self.received();
+        if received > self.last_acked
+            && (received - self.last_acked >= window as u64 / 4 || self.is_complete())
+        {
+            self.last_acked = received;
+            Some(received)
+        } else {
+            None
+        }
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor, thread, time::{Duration, Instant}
+    };
+
+    use super::{IncomingTransfer, OutgoingTransfer, TransferPart};
+    use crate::PeerId;
+
+    /// Takes parts until the window is full or the stream has ended.
+    fn take_parts(outgoing: &mut OutgoingTransfer, window: usize, parts: &mut Vec<TransferPart>) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !outgoing.finished && outgoing.sent - outgoing.acked < window as u64 {
+            match outgoing.next_part(window).unwrap() {
+                Some(part) => parts.push(part),
+                // Not read yet.
+                None => {
+                    assert!(Instant::now() < deadline, "Timed out reading the stream");
+                    thread::sleep(Duration::from_millis(1));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transfer_roundtrip() {
+        let data: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let mut outgoing = OutgoingTransfer::new(PeerId(1), Box::new(Cursor::new(data.clone())));
+        let mut parts = Vec::new();
+        take_parts(&mut outgoing, 4000, &mut parts);
+        assert_eq!(parts.len(), 4);
+        assert!(outgoing.next_part(4000).unwrap().is_none());
+        outgoing.ack(4800);
+        take_parts(&mut outgoing, 4000, &mut parts);
+        assert!(!outgoing.is_done());
+
+        let mut incoming = IncomingTransfer::new();
+        for part in parts.into_iter().rev() {
+            match part {
+                TransferPart::Data { offset, data } => assert!(incoming.add(offset, data)),
+                TransferPart::End { len } => incoming.set_len(len),
+            }
+        }
+        assert!(incoming.is_complete());
+        assert_eq!(incoming.take_ack(4000), Some(5000));
+        assert_eq!(incoming.take_ack(4000), None);
+        outgoing.ack(5000);
+        assert!(outgoing.is_done());
+        assert_eq!(incoming.into_data(), data);
+    }
+
+    #[test]
+    fn transfer_offset_overflow() {
+        let mut incoming = IncomingTransfer::new();
+        assert!(!incoming.add(u64::MAX - 1, vec![1, 2]));
+        assert_eq!(incoming.size(), 0);
+        assert!(incoming.add(u64::MAX - 2, vec![1, 2]));
+    }
+}
@@ -1,38 +1,8 @@
 use std::{
-    collections::{HashSet, VecDeque}, hash::Hash, time::{Duration, Instant}
+    collections::{HashMap, HashSet, VecDeque}, hash::Hash, io, net::UdpSocket, thread::{self, JoinHandle}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}
 };
 
-pub struct RateLimiter {
-    moments: VecDeque<Instant>,
-    time: Duration,
-    limit: usize,
-}
-
-impl RateLimiter {
-    pub fn new(limit: usize, time: Duration) -> Self {
-        Self {
-            moments: VecDeque::with_capacity(limit),
-            time,
-            limit,
-        }
-    }
-    pub fn get_token(&mut self) -> bool {
-        let now = Instant::now();
-        while self
-            .moments
-            .front()
-            .map_or(false, |moment| now - *moment > self.time)
-        {
-            self.moments.pop_front();
-        }
-        if self.moments.len() < self.limit {
-            self.moments.push_back(now);
-            true
-        } else {
-            false
-        }
-    }
-}
+use crate::{PeerStats, SeqId};
 
 pub struct RingSet<Key: Hash + Eq + Clone> {
     set: HashSet<Key>,
@@ -66,23 +36,603 @@ impl<Key: Hash + Eq + Clone> RingSet<Key> {
     }
 }
 
+/// Estimates round-trip time and derives a resend timeout from it, as described in RFC 6298.
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+    const MIN_TIMEOUT: Duration = Duration::from_millis(20);
+    const MAX_TIMEOUT: Duration = Duration::from_secs(10);
+
+    pub fn new() -> Self {
+        Self {
+            srtt: None,
+            rttvar: Duration::ZERO,
+        }
+    }
+
+    pub fn add_sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (self.rttvar * 3 + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((srtt * 7 + rtt) / 8);
+            }
+        }
+    }
+
+    /// Smoothed round-trip time, if there were any samples.
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    /// Time to wait for a confirm of a message that has already been resent `resends` times.
+    /// Doubles with every resend.
+    pub fn resend_timeout(&self, resends: u32) -> Duration {
+        let base = match self.srtt {
+            Some(srtt) => (srtt + self.rttvar * 4).clamp(Self::MIN_TIMEOUT, Self::MAX_TIMEOUT),
+            None => Self::INITIAL_TIMEOUT,
+        };
+        base.saturating_mul(1 << resends.min(16))
+            .min(Self::MAX_TIMEOUT)
+    }
+}
+
+/// Decides how many packets per second can be sent to a peer.
+/// The rate grows while packets get through, exponentially at first and linearly after the first sign of congestion.
+/// It is halved when a packet is lost or when round-trip time rises well above the lowest one seen, which means packets queue up somewhere.
+pub struct CongestionControl {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+    slow_start: bool,
+    min_rtt: Option<Duration>,
+    last_decrease: Option<Instant>,
+    last_limited: Option<Instant>,
+}
+
+impl CongestionControl {
+    pub const INITIAL_RATE: f64 = 256.0;
+    const MIN_RATE: f64 = 16.0;
+    const MAX_RATE: f64 = 65536.0;
+    /// Growth of the rate per second once out of slow start, as long as there is enough to send.
+    const INCREASE: f64 = 64.0;
+    /// Round-trip time above the lowest one that is considered to be caused by queueing.
+    const DELAY_THRESHOLD: Duration = Duration::from_millis(50);
+    /// Amount of packets that can be sent at once, in seconds worth of rate.
+    const BURST: f64 = 0.05;
+    const MIN_BURST: f64 = 4.0;
+    /// For how long the rate is allowed to grow after it has actually limited the sending.
+    const LIMITED_PERIOD: Duration = Duration::from_secs(1);
+
+    pub fn new() -> Self {
+        Self {
+            rate: Self::INITIAL_RATE,
+            tokens: Self::MIN_BURST,
+            last_refill: Instant::now(),
+            slow_start: true,
+            min_rtt: None,
+            last_decrease: None,
+            last_limited: None,
+        }
+    }
+
+    /// Current send rate, in packets per second.
+    pub fn rate(&self) -> f64 {
+        self.rate
+    }
+
+    pub fn get_token(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        let burst = (self.rate * Self::BURST).max(Self::MIN_BURST);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(burst);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            self.last_limited = Some(now);
+            false
+        }
+    }
+
+    /// Called when a packet gets confirmed, with a round-trip time sample if it could be measured.
+    pub fn on_ack(&mut self, now: Instant, rtt: Option<Duration>) {
+        if let Some(rtt) = rtt {
+            let min_rtt = self.min_rtt.map_or(rtt, |min_rtt| min_rtt.min(rtt));
+            self.min_rtt = Some(min_rtt);
+            if rtt > min_rtt + Self::DELAY_THRESHOLD {
+                self.decrease(now, rtt);
+                return;
+            }
+        }
+        // Without sending as much as allowed there is no telling whether the link can take more.
+        if self
+            .last_limited
+            .is_none_or(|limited| now.saturating_duration_since(limited) >= Self::LIMITED_PERIOD)
+        {
+            return;
+        }
+        let increase = if self.slow_start {
+            1.0
+        } else {
+            Self::INCREASE / self.rate
+        };
+        self.rate = (self.rate + increase).min(Self::MAX_RATE);
+    }
+
+    /// Called when a packet has to be resent. `rtt` is the current round-trip time estimate.
+    pub fn on_loss(&mut self, now: Instant, rtt: Duration) {
+        self.decrease(now, rtt);
+    }
+
+    /// Halves the rate, at most once per round trip, as packets sent before the previous decrease can still be affected.
+    fn decrease(&mut self, now: Instant, rtt: Duration) {
+        if self
+            .last_decrease
+            .is_some_and(|decrease| now.saturating_duration_since(decrease) < rtt)
+        {
+            return;
+        }
+        self.last_decrease = Some(now);
+        self.slow_start = false;
+        self.rate = (self.rate / 2.0).max(Self::MIN_RATE);
+    }
+}
+
+/// Limits the amount of bytes sent per second.
+/// Sending is allowed while there is any budget left, so a single message can overdraw it, which has to be paid back before anything else is sent.
+pub struct ByteBudget {
+    rate: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl ByteBudget {
+    /// Budget doesn't accumulate for longer than this while nothing is sent.
+    const BURST: Duration = Duration::from_millis(100);
+
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64 * Self::BURST.as_secs_f64(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: u64) {
+        self.rate = rate;
+    }
+
+    /// Whether there is any budget left.
+    pub fn allows(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.last_refill = now;
+        let burst = self.rate as f64 * Self::BURST.as_secs_f64();
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(burst);
+        self.tokens > 0.0
+    }
+
+    pub fn spend(&mut self, bytes: usize) {
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Sets the Don't Fragment flag on outgoing datagrams, so that ones too large for the path are dropped instead of being split up.
+/// Otherwise MTU discovery would settle on sizes that only get through as IP fragments.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let (level, name, value) = if socket.local_addr()?.is_ipv4() {
+        (
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            libc::IP_PMTUDISC_DO,
+        )
+    } else {
+        (
+            libc::IPPROTO_IPV6,
+            libc::IPV6_MTU_DISCOVER,
+            libc::IPV6_PMTUDISC_DO,
+        )
+    };
+    // SAFETY: The option value is a valid c_int that lives for the duration of the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            (&value as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Other platforms leave datagrams as they are, where MTU discovery may settle on sizes that only get through as IP fragments.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// Finds the largest datagram that gets through to a peer, by sending padded probes and waiting for them to be confirmed.
+/// Sizes between the largest one known to get through and the largest one not ruled out yet are binary searched.
+/// Once the search is done, the found size is probed again from time to time, and the search starts over from the default size if that fails.
+pub struct MtuProber {
+    default: usize,
+    current: usize,
+    high: usize,
+    probe: Option<Probe>,
+    losses: u32,
+    next_probe: Instant,
+}
+
+struct Probe {
+    seq_id: SeqId,
+    len: usize,
+    sent: Instant,
+}
+
+impl MtuProber {
+    /// The search stops once the range of sizes that could still get through is this narrow.
+    const GRANULARITY: usize = 16;
+    /// A size is considered to not get through after this many probes of it are lost in a row.
+    const MAX_LOSSES: u32 = 3;
+    const RECHECK_PERIOD: Duration = Duration::from_secs(30);
+
+    pub fn new(default: usize, max: usize) -> Self {
+        Self {
+            default,
+            current: default,
+            high: max,
+            probe: None,
+            losses: 0,
+            next_probe: Instant::now(),
+        }
+    }
+
+    /// Largest size known to get through.
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    fn searching(&self) -> bool {
+        self.high - self.current >= Self::GRANULARITY
+    }
+
+    /// Size of the probe to send, if it's time to send one. A probe that wasn't confirmed in `timeout` is considered lost.
+    pub fn next_probe(&mut self, now: Instant, timeout: Duration) -> Option<usize> {
+        if let Some(probe) = &self.probe {
+            if now.saturating_duration_since(probe.sent) < timeout {
+                return None;
+            }
+            let len = probe.len;
+            self.probe = None;
+            self.losses += 1;
+            if self.losses >= Self::MAX_LOSSES {
+                self.losses = 0;
+                if self.searching() {
+                    self.high = len - 1;
+                } else if self.current > self.default {
+                    // The path has changed, what used to get through no longer does.
+                    self.high = self.current - 1;
+                    self.current = self.default;
+                }
+                if !self.searching() {
+                    self.next_probe = now + Self::RECHECK_PERIOD;
+                }
+            }
+        }
+        if now < self.next_probe {
+            return None;
+        }
+        Some(if self.searching() {
+            (self.current + self.high).div_ceil(2)
+        } else {
+            self.current
+        })
+    }
+
+    pub fn probe_sent(&mut self, seq_id: SeqId, len: usize, now: Instant) {
+        self.probe = Some(Probe {
+            seq_id,
+            len,
+            sent: now,
+        });
+    }
+
+    pub fn on_ack(&mut self, seq_id: SeqId, now: Instant) {
+        let Some(probe) = self.probe.take_if(|probe| probe.seq_id == seq_id) else {
+            return;
+        };
+        self.losses = 0;
+        self.current = self.current.max(probe.len);
+        if !self.searching() {
+            self.next_probe = now + Self::RECHECK_PERIOD;
+        }
+    }
+}
+
+/// Wall clock time, which unlike `Instant` can be compared between machines. Used for ping timestamps.
+pub fn unix_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_micros() as u64)
+}
+
+/// Smooths the jitter and clock offsets measured by pings into `PeerStats`.
+/// The round-trip time is smoothed by the `RttEstimator` of the peer, which the pings are fed into as well.
+#[derive(Default)]
+pub struct LatencyTracker {
+    stats: Option<PeerStats>,
+}
+
+impl LatencyTracker {
+    pub fn on_pong(&mut self, ping: Duration, rtt: Duration, clock_offset: i64) -> PeerStats {
+        let stats = match self.stats {
+            None => PeerStats {
+                ping,
+                rtt,
+                jitter: Duration::ZERO,
+                clock_offset,
+            },
+            // Same gains as RTP uses for jitter.
+            Some(prev) => PeerStats {
+                ping,
+                rtt,
+                jitter: (prev.jitter * 15 + ping.abs_diff(prev.ping)) / 16,
+                clock_offset: prev.clock_offset + (clock_offset - prev.clock_offset) / 8,
+            },
+        };
+        self.stats = Some(stats);
+        stats
+    }
+}
+
+/// Joins a thread, unless it is still running at `deadline`, in which case it is left to stop on its own. Returns whether it has stopped.
+pub fn join_until(thread: JoinHandle<()>, deadline: Option<Instant>) -> bool {
+    if let Some(deadline) = deadline {
+        while !thread.is_finished() {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+    thread.join().ok();
+    true
+}
+
+/// A number that is hard to guess, for session tokens. Comes from the random number generator of the OS.
+pub fn random_u64() -> u64 {
+    let mut bytes = [0; 8];
+    getrandom::getrandom(&mut bytes).expect("OS random number generator is unavailable");
+    u64::from_le_bytes(bytes)
+}
+
+/// Whether `a` comes after `b`, taking wraparound into account.
+pub fn seq_newer(a: SeqId, b: SeqId) -> bool {
+    a != b && a.wrapping_sub(b) <= SeqId::MAX / 2
+}
+
+/// Remembers which of the recent sequence ids have been seen, taking wraparound into account.
+pub struct SeqWindow {
+    latest: Option<SeqId>,
+    seen: Vec<bool>,
+}
+
+impl SeqWindow {
+    /// Amount of sequence ids before the latest one that are tracked. Divides the range of `SeqId`, so that indices stay consistent on wraparound.
+    pub const SIZE: usize = 1024;
+
+    pub fn new() -> Self {
+        Self {
+            latest: None,
+            seen: vec![false; Self::SIZE],
+        }
+    }
+
+    /// Newest sequence id seen so far.
+    pub fn latest(&self) -> Option<SeqId> {
+        self.latest
+    }
+
+    /// Whether this sequence id has been seen.
+    /// Ids too old to be tracked are reported as seen, as they can't be told apart from duplicates.
+    pub fn contains(&self, seq_id: SeqId) -> bool {
+        match self.latest {
+            None => false,
+            Some(latest) if seq_newer(seq_id, latest) => false,
+            Some(latest) => {
+                latest.wrapping_sub(seq_id) as usize >= Self::SIZE
+                    || self.seen[seq_id as usize % Self::SIZE]
+            }
+        }
+    }
+
+    /// Whether this sequence id is recent enough to be tracked, and has been seen.
+    pub fn recorded(&self, seq_id: SeqId) -> bool {
+        self.latest.is_some_and(|latest| {
+            !seq_newer(seq_id, latest)
+                && (latest.wrapping_sub(seq_id) as usize) < Self::SIZE
+                && self.seen[seq_id as usize % Self::SIZE]
+        })
+    }
+
+    pub fn insert(&mut self, seq_id: SeqId) {
+        match self.latest {
+            Some(latest) if !seq_newer(seq_id, latest) => {}
+            Some(latest) => {
+                let advance = (seq_id.wrapping_sub(latest) as usize).min(Self::SIZE);
+                for i in 1..=advance {
+                    self.seen[latest.wrapping_add(i as SeqId) as usize % Self::SIZE] = false;
+                }
+                self.latest = Some(seq_id);
+            }
+            None => self.latest = Some(seq_id),
+        }
+        if !self.contains(seq_id) {
+            self.seen[seq_id as usize % Self::SIZE] = true;
+        }
+    }
+}
+
+/// Holds items that arrived ahead of their turn and releases them in sequence order.
+pub struct ReorderBuffer<T> {
+    next: SeqId,
+    pending: HashMap<SeqId, T>,
+    limit: usize,
+}
+
+impl<T> ReorderBuffer<T> {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            next: 0,
+            pending: HashMap::new(),
+            limit,
+        }
+    }
+
+    /// Whether an item with this id can be pushed without being lost, i.e. it is either within the buffer's window or was already released.
+    pub fn accepts(&self, id: SeqId) -> bool {
+        let ahead = id.wrapping_sub(self.next) as usize;
+        ahead < self.limit || ahead > SeqId::MAX as usize / 2
+    }
+
+    /// Whether an item with this id would be stored until the ones before it arrive, instead of being released or discarded right away.
+    pub fn holds_back(&self, id: SeqId) -> bool {
+        let ahead = id.wrapping_sub(self.next) as usize;
+        ahead > 0 && ahead < self.limit
+    }
+
+    /// Accepts an item with a specified id, returning all of the items that are ready to be released, in order.
+    /// Items that are too far ahead or were already released are discarded.
+    pub fn push(&mut self, id: SeqId, item: T) -> Vec<T> {
+        let ahead = id.wrapping_sub(self.next) as usize;
+        if ahead >= self.limit {
+            return Vec::new();
+        }
+        self.pending.insert(id, item);
+        let mut ready = Vec::new();
+        while let Some(item) = self.pending.remove(&self.next) {
+            ready.push(item);
+            self.next = self.next.wrapping_add(1);
+        }
+        ready
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::{thread, time::Duration};
+    use std::time::{Duration, Instant};
+
+    use super::{
+        seq_newer, ByteBudget, CongestionControl, LatencyTracker, MtuProber, ReorderBuffer, RingSet, RttEstimator, SeqWindow
+    };
 
-    use super::{RateLimiter, RingSet};
+    #[test]
+    fn latency_tracker() {
+        let mut tracker = LatencyTracker::default();
+        let stats = tracker.on_pong(Duration::from_millis(100), Duration::from_millis(100), 800);
+        assert_eq!(stats.rtt, Duration::from_millis(100));
+        assert_eq!(stats.jitter, Duration::ZERO);
+        assert_eq!(stats.clock_offset, 800);
+        let stats = tracker.on_pong(Duration::from_millis(180), Duration::from_millis(110), 0);
+        assert_eq!(stats.ping, Duration::from_millis(180));
+        assert_eq!(stats.rtt, Duration::from_millis(110));
+        assert_eq!(stats.jitter, Duration::from_millis(5));
+        assert_eq!(stats.clock_offset, 700);
+    }
 
     #[test]
-    fn rate_limit() {
-        let duration = Duration::from_micros(100);
-        let mut limiter = RateLimiter::new(4, duration);
+    fn mtu_prober() {
+        let timeout = Duration::from_millis(100);
+        let mut prober = MtuProber::new(1000, 5000);
+        let mut now = Instant::now();
+        let mut seq_id = 0;
+        // Only datagrams of up to 2000 bytes get through.
+        while let Some(len) = prober.next_probe(now, timeout) {
+            prober.probe_sent(seq_id, len, now);
+            if len <= 2000 {
+                prober.on_ack(seq_id, now);
+            } else {
+                now += timeout;
+            }
+            seq_id += 1;
+        }
+        assert!((2000 - 16..=2000).contains(&prober.current()));
 
-        for _ in 0..4 {
-            assert!(limiter.get_token())
+        // Path changes, so that the found size no longer gets through.
+        now += Duration::from_secs(60);
+        for _ in 0..3 {
+            let len = prober.next_probe(now, timeout).unwrap();
+            assert_eq!(len, prober.current());
+            prober.probe_sent(seq_id, len, now);
+            seq_id += 1;
+            now += timeout;
         }
-        assert!(!limiter.get_token());
-        thread::sleep(duration * 2);
-        assert!(limiter.get_token());
+        let len = prober.next_probe(now, timeout).unwrap();
+        assert_eq!(prober.current(), 1000);
+        assert!(len > 1000 && len < 2000);
+    }
+
+    #[test]
+    fn byte_budget() {
+        let mut budget = ByteBudget::new(10_000);
+        let now = Instant::now();
+        assert!(budget.allows(now));
+        budget.spend(1500);
+        assert!(!budget.allows(now));
+        assert!(!budget.allows(now + Duration::from_millis(40)));
+        assert!(budget.allows(now + Duration::from_millis(60)));
+        // Doesn't accumulate past the burst.
+        let later = now + Duration::from_secs(10);
+        assert!(budget.allows(later));
+        budget.spend(1001);
+        assert!(!budget.allows(later));
+    }
+
+    #[test]
+    fn congestion_control() {
+        let mut cc = CongestionControl::new();
+        let now = Instant::now();
+        let rtt = Duration::from_millis(10);
+        while cc.get_token(now) {}
+        assert!(cc.get_token(now + Duration::from_millis(100)));
+
+        // Slow start.
+        for _ in 0..256 {
+            cc.on_ack(now, Some(rtt));
+        }
+        assert_eq!(cc.rate(), 512.0);
+
+        cc.on_loss(now, rtt);
+        assert_eq!(cc.rate(), 256.0);
+        // Still the same round trip.
+        cc.on_loss(now + rtt / 2, rtt);
+        assert_eq!(cc.rate(), 256.0);
+        cc.on_ack(now, Some(rtt));
+        assert_eq!(cc.rate(), 256.25);
+
+        // Queueing delay.
+        cc.on_ack(now + rtt * 20, Some(Duration::from_millis(100)));
+        assert_eq!(cc.rate(), 128.125);
+
+        // Not sending as much as allowed.
+        let later = now + Duration::from_secs(5);
+        cc.on_ack(later, Some(rtt));
+        assert_eq!(cc.rate(), 128.125);
     }
 
     #[test]
@@ -100,4 +650,79 @@ mod tests {
         assert!(!set.contains(&1));
         assert!(set.contains(&4));
     }
+
+    #[test]
+    fn rtt_estimator() {
+        let mut rtt = RttEstimator::new();
+        assert_eq!(rtt.srtt(), None);
+        assert_eq!(rtt.resend_timeout(0), Duration::from_secs(1));
+        rtt.add_sample(Duration::from_millis(100));
+        assert_eq!(rtt.srtt(), Some(Duration::from_millis(100)));
+        assert_eq!(rtt.resend_timeout(0), Duration::from_millis(300));
+        assert_eq!(rtt.resend_timeout(1), Duration::from_millis(600));
+        assert_eq!(rtt.resend_timeout(10), Duration::from_secs(10));
+        for _ in 0..100 {
+            rtt.add_sample(Duration::from_millis(1));
+        }
+        assert_eq!(rtt.resend_timeout(0), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn seq_order() {
+        assert!(seq_newer(1, 0));
+        assert!(!seq_newer(0, 1));
+        assert!(!seq_newer(5, 5));
+        assert!(seq_newer(2, u16::MAX - 2));
+        assert!(!seq_newer(u16::MAX - 2, 2));
+    }
+
+    #[test]
+    fn seq_window() {
+        let mut window = SeqWindow::new();
+        assert!(!window.contains(5));
+        window.insert(5);
+        assert!(window.contains(5));
+        assert!(!window.contains(4));
+        window.insert(3);
+        assert!(window.contains(3));
+        assert_eq!(window.latest(), Some(5));
+
+        let mut window = SeqWindow::new();
+        for seq_id in (0..=u16::MAX).chain(0..100) {
+            assert!(!window.contains(seq_id));
+            window.insert(seq_id);
+            assert!(window.contains(seq_id));
+        }
+        assert_eq!(window.latest(), Some(99));
+        assert!(window.contains(u16::MAX));
+        assert!(!window.contains(100));
+        // Too old to be tracked.
+        assert!(window.contains(50_000));
+        assert!(!window.recorded(50_000));
+        assert!(window.recorded(99));
+        assert!(window.recorded(u16::MAX));
+        assert!(!window.recorded(100));
+
+        window.insert(3000);
+        assert!(!window.contains(2999));
+        assert!(window.contains(3000));
+    }
+
+    #[test]
+    fn reorder_buffer() {
+        let mut buffer = ReorderBuffer::new(4);
+        assert_eq!(buffer.push(1, 'b'), vec![]);
+        assert_eq!(buffer.push(2, 'c'), vec![]);
+        assert_eq!(buffer.push(0, 'a'), vec!['a', 'b', 'c']);
+        assert_eq!(buffer.push(1, 'b'), vec![]);
+        assert_eq!(buffer.push(7, 'h'), vec![]);
+        assert_eq!(buffer.push(3, 'd'), vec!['d']);
+        assert!(buffer.accepts(2));
+        assert!(buffer.accepts(7));
+        assert!(!buffer.accepts(8));
+        assert!(buffer.holds_back(5));
+        assert!(!buffer.holds_back(4));
+        assert!(!buffer.holds_back(2));
+        assert!(!buffer.holds_back(8));
+    }
 }